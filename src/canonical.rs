@@ -0,0 +1,49 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+//! The canonical BSON encoding of a document: what gets signed and authenticated, so that two
+//! implementations agree on the bytes no matter how they build and serialize a document.
+//!
+//! See "Field ordering" in `burrito_docs/README.md`.
+
+use crate::error::Result;
+use bson::{Bson, Document};
+use serde::Serialize;
+
+/// Doubles beyond this lose integer precision, so they are never taken for integers.
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// Encodes `value` canonically. It must serialize to a document.
+pub fn to_canonical_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    Ok(bson::to_vec(&canonicalize(bson::to_document(value)?))?)
+}
+
+/// Sorts the keys of `document` and every document nested in it (arrays keep their order), and
+/// writes every integer the same way:
+///
+/// - `Int32` becomes `Int64`,
+/// - a `Double` holding an integer (such as `2.0`, which is how JavaScript writes `2`) becomes `Int64`.
+///
+/// Dates need nothing: a BSON `DateTime` is always milliseconds since the epoch, in UTC, with no
+/// time zone or precision to disagree on. A `Timestamp` is MongoDB's internal replication clock,
+/// not a date, so it is kept as it is, and dates written as strings are strings.
+pub fn canonicalize(document: Document) -> Document {
+    let mut fields: Vec<(String, Bson)> = document.into_iter().map(|(key, value)| (key, canonicalize_value(value))).collect();
+
+    // Keys are unique, so this is the same order as a B-Tree Map: by the UTF-8 bytes of the keys.
+    fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    fields.into_iter().collect()
+}
+
+fn canonicalize_value(value: Bson) -> Bson {
+    match value {
+        Bson::Document(document) => Bson::Document(canonicalize(document)),
+        Bson::Array(array) => Bson::Array(array.into_iter().map(canonicalize_value).collect()),
+        Bson::Int32(int) => Bson::Int64(int.into()),
+        Bson::Double(double) if double.fract() == 0.0 && double.abs() <= MAX_SAFE_INTEGER => Bson::Int64(double as i64),
+        other => other,
+    }
+}
@@ -5,6 +5,7 @@
  */
 use std::collections::BTreeMap;
 use serde::Serialize;
+use crate::error::Result;
 use crate::waiters::Waiter;
 
 pub type Entry = bson::Document;
@@ -12,27 +13,29 @@ pub type Entry = bson::Document;
 pub trait Metadata: Sized {
     fn get_meta(&self, key: &str) -> Option<&bson::Bson>;
 
-    fn set_meta(&mut self, metadata: (&str, impl Serialize));
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()>;
 
-    fn add_meta(&mut self, metadata: (&str, impl Serialize)) {
+    fn add_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
         if self.get_meta(metadata.0).is_none() {
-            self.set_meta(metadata);
+            self.set_meta(metadata)?;
         }
+
+        Ok(())
     }
 
-    fn with_meta(mut self, metadata: (&str, impl Serialize)) -> Self {
-        self.set_meta(metadata);
-        self
+    fn with_meta(mut self, metadata: (&str, impl Serialize)) -> Result<Self> {
+        self.set_meta(metadata)?;
+        Ok(self)
     }
 
-    fn and_meta(mut self, metadata: (&str, impl Serialize)) -> Self {
-        self.add_meta(metadata);
-        self
+    fn and_meta(mut self, metadata: (&str, impl Serialize)) -> Result<Self> {
+        self.add_meta(metadata)?;
+        Ok(self)
     }
 
-    fn and_defaults<T: Waiter>(self) -> Self {
-        self.with_meta(("waiter", T::name()))
-            .with_meta(("version", T::version()))
+    fn and_defaults<T: Waiter>(self) -> Result<Self> {
+        self.with_meta(("waiter", T::name()))?
+            .with_meta(("version", T::version()))?
             .and_meta(("created", bson::DateTime::now()))
     }
 }
@@ -42,10 +45,11 @@ impl Metadata for Entry {
         self.get(key)
     }
 
-    fn set_meta(&mut self, metadata: (&str, impl Serialize)) {
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
         let (key, value) = metadata;
-        let value = bson::to_bson(&value).expect("Failed to serialize metadata");
+        let value = bson::to_bson(&value)?;
         self.insert(key, value);
+        Ok(())
     }
 }
 
@@ -54,7 +58,8 @@ impl Metadata for BTreeMap<String, bson::Bson> {
         self.get(key)
     }
 
-    fn set_meta(&mut self, metadata: (&str, impl Serialize)) {
-        self.insert(metadata.0.to_string(), bson::to_bson(&metadata.1).unwrap());
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
     }
 }
\ No newline at end of file
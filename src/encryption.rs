@@ -3,20 +3,142 @@
  *
  * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
  */
+use bson::spec::BinarySubtype;
 use dryoc::dryocbox::protected::{PublicKey, SecretKey};
+use dryoc::protected::{HeapBytes, LockedBytes, NewLockedFromSlice};
+use dryoc::types::{Bytes, MutBytes};
 use crate::database::Entry;
+use crate::error::Result;
 use crate::waiters::Waiter;
 
 pub trait EncryptionWaiter: Waiter {
-    fn encrypt(entry: Entry, key: PublicKey) -> anyhow::Result<Self>;
-    fn decrypt(self, key: SecretKey) -> anyhow::Result<Entry>;
+    fn encrypt(entry: Entry, key: PublicKey) -> Result<Self>;
+    fn decrypt(self, key: SecretKey) -> Result<Entry>;
 }
 
 pub trait EncryptionWaiterSymmetric: Waiter {
-    fn encrypt_sym(entry: Entry, key: SecretKey) -> anyhow::Result<Self>;
-    fn decrypt_sym(self, key: SecretKey) -> anyhow::Result<Entry>;
+    fn encrypt_sym(entry: Entry, key: SecretKey) -> Result<Self>;
+    fn decrypt_sym(self, key: SecretKey) -> Result<Entry>;
 }
 
+/// Waiters that know how to turn some secret material (a password, a `CompositeKey`...) into their key.
 pub trait KeyFrom: Waiter {
-    fn key_from(&self, data: Vec<u8>) -> anyhow::Result<SecretKey>;
+    fn key_from(&self, data: Vec<u8>) -> Result<SecretKey>;
+}
+
+pub(crate) fn blake2b(input: &[u8], key: Option<&[u8]>, output_len: usize) -> Result<Vec<u8>> {
+    use dryoc::classic::crypto_generichash::crypto_generichash;
+
+    let mut output = vec![0u8; output_len];
+    crypto_generichash(&mut output, input, key)?;
+
+    Ok(output)
+}
+
+/// A short, non-secret name for a public key (box or signing): the first 16 bytes of its BLAKE2b hash.
+pub fn public_key_id(public_key: &[u8]) -> Result<bson::Binary> {
+    Ok(bson::Binary {
+        subtype: BinarySubtype::Generic,
+        bytes: blake2b(public_key, None, 16)?,
+    })
+}
+
+/// A short, non-secret name for a symmetric key: 16 bytes of BLAKE2b, keyed with the key itself.
+/// Without the key, the id says nothing about it.
+pub fn symmetric_key_id(key: &SecretKey) -> Result<bson::Binary> {
+    Ok(bson::Binary {
+        subtype: BinarySubtype::Generic,
+        bytes: blake2b(b"burrito key id", Some(key.as_slice()), 16)?,
+    })
+}
+
+/// A master key made of several factors, like KeePass' composite keys. Every factor that is set
+/// must be supplied again to unlock.
+///
+/// The factors are hashed separately, then hashed together. The result is not a key yet: it is
+/// the secret handed to [`KeyFrom::key_from`], so it still goes through the box's password hash.
+/// As in the `Keyring`, the hashes live in locked memory that is zeroed on drop.
+#[derive(Clone, Default)]
+pub struct CompositeKey {
+    password: Option<LockedBytes>,
+    key_file: Option<LockedBytes>,
+    challenge: Option<LockedBytes>,
+}
+
+/// BLAKE2b straight into locked memory, so the hash of a secret never sits in a plain `Vec`.
+fn locked_blake2b(input: &[u8], output_len: usize) -> Result<LockedBytes> {
+    use dryoc::classic::crypto_generichash::crypto_generichash;
+
+    let mut output = HeapBytes::from_slice_into_locked(&vec![0u8; output_len])?;
+    crypto_generichash(output.as_mut_slice(), input, None)?;
+
+    Ok(output)
+}
+
+impl CompositeKey {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn password(mut self, password: &str) -> Result<Self> {
+        self.password = Some(locked_blake2b(password.as_bytes(), 32)?);
+        Ok(self)
+    }
+
+    /// `key_file` is either a `burrito_key_file` document, in which case only its `KEY` counts, or
+    /// any other file, in which case every byte counts. A file that names the `burrito_key_file`
+    /// waiter but doesn't parse as one is an error rather than a raw key file.
+    pub fn key_file(mut self, key_file: &[u8]) -> Result<Self> {
+        use crate::waiters::key_file::KeyFile;
+
+        let name = KeyFile::name();
+        let claims_structured = key_file.windows(name.len()).any(|window| window == name.as_bytes());
+
+        let key_file = if claims_structured {
+            locked_blake2b(&KeyFile::from_bytes(key_file)?.key.bytes, 32)?
+        } else {
+            locked_blake2b(key_file, 32)?
+        };
+
+        self.key_file = Some(key_file);
+        Ok(self)
+    }
+
+    pub fn key_file_path(self, path: impl AsRef<std::path::Path>) -> Result<Self> {
+        self.key_file(&std::fs::read(path)?)
+    }
+
+    /// An extra secret, e.g. the response of a hardware token to a fixed challenge.
+    pub fn challenge(mut self, secret: &[u8]) -> Result<Self> {
+        self.challenge = Some(locked_blake2b(secret, 32)?);
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.password.is_none() && self.key_file.is_none() && self.challenge.is_none()
+    }
+
+    pub fn to_bytes(&self) -> Result<LockedBytes> {
+        use crate::error::Error;
+
+        if self.is_empty() {
+            return Err(Error::Kdf("composite key has no factors".to_string()));
+        }
+
+        let factors = [(b'P', &self.password), (b'K', &self.key_file), (b'C', &self.challenge)];
+        let len: usize = factors.iter().filter_map(|(_, factor)| factor.as_ref()).map(|factor| 1 + factor.len()).sum();
+
+        // Tag each factor, so that a password can never stand in for a key file with the same hash.
+        let mut combined = HeapBytes::from_slice_into_locked(&vec![0u8; len])?;
+        let mut offset = 0;
+        for (tag, factor) in factors {
+            if let Some(factor) = factor {
+                combined[offset] = tag;
+                combined[offset + 1..offset + 1 + factor.len()].copy_from_slice(factor);
+                offset += 1 + factor.len();
+            }
+        }
+
+        locked_blake2b(&combined, 64)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,129 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use dryoc::types::ByteArray;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Version mismatch for {waiter}: expected {expected}, got {found}")]
+    VersionMismatch { waiter: String, expected: String, found: String },
+    #[error("Wrong waiter: expected {expected}, got {found}")]
+    WrongWaiter { expected: String, found: String },
+    #[error("Unknown waiter: {0}")]
+    UnknownWaiter(String),
+    #[error("Entry is missing the field `{0}`")]
+    MissingField(String),
+    #[error("Entry field `{field}` is invalid: {reason}")]
+    InvalidField { field: String, reason: String },
+    #[error("No entry at `{0}`")]
+    PathNotFound(String),
+    #[error("`{0}` is not a group")]
+    NotAGroup(String),
+    #[error("Invalid path: {0}")]
+    InvalidPath(String),
+    #[error("Message authentication failed: wrong key, or the data was tampered with")]
+    MacFailure,
+    #[error("Bad key length: expected {expected} bytes, got {found}")]
+    BadKeyLength { expected: usize, found: usize },
+    #[error("The key is not one of the recipients of this box")]
+    NotARecipient,
+    #[error("A box needs at least one recipient")]
+    NoRecipients,
+    #[error("A recipient of this box has no key in the keyring")]
+    UnknownRecipient,
+    #[error("The key is not the one named by the KEY_ID of this box")]
+    KeyMismatch,
+    #[error("No key in the keyring opens this box")]
+    NoMatchingKey,
+    #[error("No key called `{0}` in the keyring")]
+    UnknownKey(String),
+    #[error("There is already a key called `{0}` in the keyring")]
+    DuplicateKey(String),
+    #[error("The entry is signed by a key the keyring does not trust")]
+    UntrustedSigner,
+    #[error("The signing key was revoked")]
+    RevokedKey,
+    #[error("The signing key is outside of its validity window")]
+    KeyNotValid,
+    #[error("Entry does not contain a signature")]
+    MissingSignature,
+    #[error("Signature is invalid")]
+    InvalidSignature,
+    #[error("Revision history is broken: {0}")]
+    BrokenHistory(String),
+    #[error("A signature threshold must be at least 1")]
+    InvalidThreshold,
+    #[error("Not enough trusted signatures: {required} required, {found} found")]
+    ThresholdNotMet { required: usize, found: usize },
+    #[error("The file does not match its detached signature")]
+    FileMismatch,
+    #[error("Invalid or unsupported SSH key: {0}")]
+    InvalidSshKey(String),
+    #[error("ssh-agent error: {0}")]
+    SshAgent(String),
+    #[error("Invalid otpauth:// URI: {0}")]
+    InvalidUri(String),
+    #[error("Encrypted stream ended before its final chunk")]
+    Truncated,
+    #[error("The box keeps its ciphertext outside of the entry")]
+    DetachedCiphertext,
+    #[error("Encrypted box is non-standard: {0}")]
+    NonStandardBox(String),
+    #[error("Key derivation failed: {0}")]
+    Kdf(String),
+    #[error("Not a burrito vault: {0}")]
+    InvalidVault(String),
+    #[error("Unsupported vault format version: expected {expected}, got {found}")]
+    UnsupportedFormat { expected: u32, found: u32 },
+    #[error("Failed to serialize: {0}")]
+    Serialization(#[from] bson::ser::Error),
+    #[error("Failed to deserialize: {0}")]
+    Deserialization(#[from] bson::de::Error),
+    #[error("Cryptography error: {0}")]
+    Crypto(#[from] dryoc::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl Error {
+    pub(crate) fn invalid_field(field: &str, reason: impl ToString) -> Self {
+        Self::InvalidField { field: field.to_string(), reason: reason.to_string() }
+    }
+}
+
+/// Reads a fixed-size key out of a BSON binary, without ever panicking on a truncated file.
+pub(crate) fn key_from_slice<const N: usize, K>(bytes: &[u8]) -> Result<K>
+where
+    K: ByteArray<N> + for<'a> TryFrom<&'a [u8]>,
+{
+    if bytes.len() != N {
+        return Err(Error::BadKeyLength { expected: N, found: bytes.len() });
+    }
+
+    K::try_from(bytes).map_err(|_| Error::BadKeyLength { expected: N, found: bytes.len() })
+}
+
+/// Like [`key_from_slice`], for the other fixed-size fields of a box (MACs and nonces).
+pub(crate) fn field_from_slice<const N: usize, T>(field: &str, bytes: &[u8]) -> Result<T>
+where
+    T: ByteArray<N> + for<'a> TryFrom<&'a [u8]>,
+{
+    if bytes.len() != N {
+        return Err(Error::invalid_field(field, format!("expected {} bytes, got {}", N, bytes.len())));
+    }
+
+    T::try_from(bytes).map_err(|_| Error::invalid_field(field, format!("expected {} bytes", N)))
+}
+
+pub(crate) fn get_str<'a>(entry: &'a bson::Document, key: &str) -> Result<&'a str> {
+    use bson::document::ValueAccessError;
+
+    entry.get_str(key).map_err(|err| match err {
+        ValueAccessError::NotPresent => Error::MissingField(key.to_string()),
+        _ => Error::invalid_field(key, "expected a string"),
+    })
+}
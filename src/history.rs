@@ -0,0 +1,190 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+//! Append-only, signed revision history of an entry, kept in its `revisions`.
+//!
+//! Every revision is a snapshot of the entry, signed by whoever made it, and linked to the revision
+//! before it by hash. Changing, dropping or reordering past revisions breaks the chain.
+//!
+//! The chain has no anchor outside the entry, so it can't tell an entry that was rolled back, with
+//! its later revisions cut off, from one that was never changed further: it only proves that what
+//! is left is a genuine history. Keep the hash of the latest revision somewhere else (a signed vault
+//! header, another device) to notice that.
+//!
+//! Every revision is a full snapshot. Old plaintext stays in `revisions` for as long as the entry
+//! exists, so a secret that was replaced or rotated out is still there, in clear inside the box
+//! around the entry. Don't keep a history on entries whose old values must be forgotten.
+
+use crate::canonical::to_canonical_vec;
+use crate::database::Entry;
+use crate::encryption::blake2b;
+use crate::error::{key_from_slice, Error, Result};
+use crate::keyring::Keyring;
+use crate::signing::{Signing, SIGNATURE_FIELDS};
+use bson::spec::BinarySubtype;
+use bson::Bson;
+use dryoc::sign::protected::{PublicKey, SecretKey};
+use dryoc::sign::{SignedMessage, SigningKeyPair};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// One of the `revisions` of an entry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Revision {
+    /// The entry as it was, without its signatures or history. See [`History::revision_content`].
+    pub content: Entry,
+    /// The [`hash`](Self::hash) of the revision before. Missing from the first one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<bson::Binary>,
+    pub public_key: bson::Binary,
+    pub signature: bson::Binary,
+}
+
+impl Revision {
+    /// BLAKE2b-256 of the canonical encoding of the whole revision, signature included.
+    pub fn hash(&self) -> Result<bson::Binary> {
+        Ok(bson::Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: blake2b(&to_canonical_vec(self)?, None, 32)?,
+        })
+    }
+
+    pub fn modified(&self) -> Option<bson::DateTime> {
+        self.content.get_datetime("modified").ok().copied()
+    }
+
+    pub fn signer(&self) -> Result<PublicKey> {
+        key_from_slice(&self.public_key.bytes)
+    }
+
+    /// Whether the signature matches the embedded public key. Whether that key is trusted is up to
+    /// the caller.
+    pub fn verify(&self) -> bool {
+        let (Ok(public_key), Ok(message)) = (self.signer(), signed_message(&self.content, &self.previous)) else { return false };
+
+        SignedMessage::from_parts(self.signature.bytes.clone(), message).verify(&public_key).is_ok()
+    }
+}
+
+pub trait History: Signing + DeserializeOwned {
+    /// What a revision keeps of the entry: everything but its signatures (which change with every
+    /// revision) and its `revisions`.
+    fn revision_content(&self) -> Result<Entry> {
+        let mut content = bson::to_document(&self)?;
+        for field in SIGNATURE_FIELDS {
+            content.remove(*field);
+        }
+        content.remove("revisions");
+
+        Ok(content)
+    }
+
+    /// The `revisions` of the entry, oldest first. No verification is done: see [`verify_history`](Self::verify_history).
+    fn history(&self) -> Result<Vec<Revision>> {
+        match self.get_meta("revisions") {
+            Some(revisions) => Ok(bson::from_bson(revisions.clone())?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Records the entry as it is now, with `modified` set, as a new revision signed with `key`.
+    /// Call it after every change.
+    fn append_revision(self, key: SecretKey) -> Result<Self> {
+        let keypair: SigningKeyPair<PublicKey, SecretKey> = SigningKeyPair::from_secret_key(key);
+
+        let out = self.with_meta(("modified", bson::DateTime::now()))?;
+        let mut revisions = out.history()?;
+
+        let content = out.revision_content()?;
+        let previous = revisions.last().map(Revision::hash).transpose()?;
+        let (signature, _data): (Vec<u8>, Vec<u8>) = keypair.sign(signed_message(&content, &previous)?)?.into_parts();
+
+        revisions.push(Revision {
+            content,
+            previous,
+            public_key: bson::Binary {
+                subtype: BinarySubtype::Sensitive,
+                bytes: keypair.public_key.to_vec(),
+            },
+            signature: bson::Binary {
+                subtype: BinarySubtype::Sensitive,
+                bytes: signature,
+            },
+        });
+
+        out.with_meta(("revisions", revisions))
+    }
+
+    /// Checks that every revision is signed, links to the one before, and that the last one is the
+    /// entry as it is now. Returns the history.
+    ///
+    /// Signers are not checked against anything: use [`TrustStore::verify_entry_history`](crate::waiters::trust_store::TrustStore::verify_entry_history)
+    /// to only accept approved keys.
+    fn verify_history(&self) -> Result<Vec<Revision>> {
+        let revisions = self.history()?;
+        let Some(last) = revisions.last() else { return Err(Error::BrokenHistory("there are no revisions".to_string())) };
+
+        let mut previous = None;
+        for (index, revision) in revisions.iter().enumerate() {
+            if revision.previous != previous {
+                return Err(Error::BrokenHistory(format!("revision {} does not follow the one before", index)));
+            }
+            if !revision.verify() {
+                return Err(Error::InvalidSignature);
+            }
+
+            previous = Some(revision.hash()?);
+        }
+
+        if to_canonical_vec(&last.content)? != to_canonical_vec(&self.revision_content()?)? {
+            return Err(Error::BrokenHistory("the entry changed since its last revision".to_string()));
+        }
+
+        Ok(revisions)
+    }
+
+    /// Brings the entry back to revision `index`. The history is kept: going back is recorded as a
+    /// new revision, signed with `key`.
+    fn rollback(self, index: usize, key: SecretKey) -> Result<Self> {
+        let revisions = self.verify_history()?;
+        let revision = revisions.get(index).ok_or_else(|| Error::BrokenHistory(format!("there is no revision {}", index)))?;
+
+        let restored: Self = bson::from_document(revision.content.clone())?;
+
+        restored
+            .with_meta(("revisions", revisions))?
+            .append_revision(key)
+    }
+}
+
+impl<T: Signing + DeserializeOwned> History for T {}
+
+/// What the signature of a revision covers: the content, and the revision it follows.
+pub(crate) fn signed_message(content: &Entry, previous: &Option<bson::Binary>) -> Result<Vec<u8>> {
+    let previous = previous.clone().map(Bson::Binary).unwrap_or(Bson::Null);
+
+    to_canonical_vec(&bson::doc! { "content": content, "previous": previous })
+}
+
+/// Brings the history of `updated`, a changed entry, up to date with a new revision. It is signed
+/// with the key of `new` named like the key of `old` that signed the last revision.
+///
+/// Used when entries are sealed or rotated on their owner's behalf. Entries without a history, or
+/// whose history is already up to date, are returned as they are.
+pub(crate) fn continue_history(updated: Entry, old: &Keyring, new: &Keyring) -> Result<Entry> {
+    let Some(last) = updated.history()?.pop() else { return Ok(updated) };
+    if updated.verify_history().is_ok() {
+        return Ok(updated);
+    }
+
+    let name = old.signing_key_name(&last.public_key.bytes).ok_or(Error::UntrustedSigner)?;
+    let key = new.signing_secret_key(name).ok_or_else(|| Error::UnknownKey(name.to_string()))?;
+
+    // Also checks the revisions before, which must not have been broken already.
+    let continued = updated.append_revision(key)?;
+    continued.verify_history()?;
+
+    Ok(continued)
+}
@@ -0,0 +1,420 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::database::Entry;
+use crate::encryption::{public_key_id, symmetric_key_id, EncryptionWaiter, EncryptionWaiterSymmetric};
+use crate::error::{get_str, key_from_slice, Error, Result};
+use crate::signing::Signing;
+use crate::waiters::burrito_aead_box::BurritoAeadBox;
+use crate::waiters::burrito_box::BurritoBox;
+use crate::waiters::burrito_box_sym::{BurritoBoxSym, KdfParams};
+use crate::waiters::burrito_multi_box::BurritoMultiBox;
+use crate::waiters::burrito_stream_box::BurritoStreamBox;
+use crate::waiters::Waiter;
+use bson::Bson;
+use dryoc::auth::protected::Key;
+use dryoc::dryocbox::protected::{PublicKey, SecretKey};
+use dryoc::keypair::KeyPair;
+use dryoc::protected::{HeapBytes, Lockable, LockedBytes, NewLockedFromSlice, Protected};
+use dryoc::sign::protected::{PublicKey as SigningPublicKey, SecretKey as SigningSecretKey};
+use dryoc::sign::SigningKeyPair;
+use dryoc::types::Bytes;
+
+/// The field of a box naming the key it was sealed with. See [`symmetric_key_id`] and [`public_key_id`].
+pub const KEY_ID: &str = "KEY_ID";
+
+type Locked<T> = Protected<T, dryoc::protected::traits::ReadWrite, dryoc::protected::traits::Locked>;
+
+struct SymmetricKey {
+    name: String,
+    id: bson::Binary,
+    key: Locked<SecretKey>,
+}
+
+struct BoxKeyPair {
+    name: String,
+    id: bson::Binary,
+    public_key: PublicKey,
+    secret_key: Locked<SecretKey>,
+}
+
+struct SigningKey {
+    name: String,
+    id: bson::Binary,
+    public_key: SigningPublicKey,
+    secret_key: Locked<SigningSecretKey>,
+}
+
+struct Password {
+    name: String,
+    password: LockedBytes,
+}
+
+/// Named keys, kept in locked memory (never swapped out, left out of core dumps): symmetric keys,
+/// box key pairs, signing key pairs and passwords.
+///
+/// Boxes name the key they were sealed with in their `KEY_ID`, so the right key is found without
+/// trying every one of them. Boxes without one are opened by trial.
+#[derive(Default)]
+pub struct Keyring {
+    symmetric_keys: Vec<SymmetricKey>,
+    box_keys: Vec<BoxKeyPair>,
+    signing_keys: Vec<SigningKey>,
+    passwords: Vec<Password>,
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the id of the key. Names are unique across every kind of key and password.
+    pub fn add_symmetric_key(&mut self, name: &str, key: SecretKey) -> Result<bson::Binary> {
+        self.check_name(name)?;
+        let id = symmetric_key_id(&key)?;
+
+        self.symmetric_keys.push(SymmetricKey {
+            name: name.to_string(),
+            id: id.clone(),
+            key: key.mlock()?,
+        });
+
+        Ok(id)
+    }
+
+    /// Adds the key pair of `secret_key`. Returns the id of its public key.
+    pub fn add_box_key(&mut self, name: &str, secret_key: SecretKey) -> Result<bson::Binary> {
+        self.check_name(name)?;
+        let keypair: KeyPair<PublicKey, SecretKey> = KeyPair::from_secret_key(secret_key);
+        let id = public_key_id(keypair.public_key.as_slice())?;
+
+        self.box_keys.push(BoxKeyPair {
+            name: name.to_string(),
+            id: id.clone(),
+            public_key: keypair.public_key.clone(),
+            secret_key: keypair.secret_key.clone().mlock()?,
+        });
+
+        Ok(id)
+    }
+
+    /// Adds the key pair of `secret_key`. Returns the id of its public key.
+    pub fn add_signing_key(&mut self, name: &str, secret_key: SigningSecretKey) -> Result<bson::Binary> {
+        self.check_name(name)?;
+        let keypair: SigningKeyPair<SigningPublicKey, SigningSecretKey> = SigningKeyPair::from_secret_key(secret_key);
+        let id = public_key_id(keypair.public_key.as_slice())?;
+
+        self.signing_keys.push(SigningKey {
+            name: name.to_string(),
+            id: id.clone(),
+            public_key: keypair.public_key.clone(),
+            secret_key: keypair.secret_key.clone().mlock()?,
+        });
+
+        Ok(id)
+    }
+
+    pub fn add_password(&mut self, name: &str, password: &str) -> Result<()> {
+        self.check_name(name)?;
+        self.passwords.push(Password {
+            name: name.to_string(),
+            password: HeapBytes::from_slice_into_locked(password.as_bytes())?,
+        });
+
+        Ok(())
+    }
+
+    pub fn with_symmetric_key(mut self, name: &str, key: SecretKey) -> Result<Self> {
+        self.add_symmetric_key(name, key)?;
+        Ok(self)
+    }
+
+    pub fn with_box_key(mut self, name: &str, secret_key: SecretKey) -> Result<Self> {
+        self.add_box_key(name, secret_key)?;
+        Ok(self)
+    }
+
+    pub fn with_signing_key(mut self, name: &str, secret_key: SigningSecretKey) -> Result<Self> {
+        self.add_signing_key(name, secret_key)?;
+        Ok(self)
+    }
+
+    pub fn with_password(mut self, name: &str, password: &str) -> Result<Self> {
+        self.add_password(name, password)?;
+        Ok(self)
+    }
+
+    /// Fails if a key or password is already called `name`.
+    fn check_name(&self, name: &str) -> Result<()> {
+        let taken = self.symmetric_keys.iter().any(|key| key.name == name)
+            || self.box_keys.iter().any(|key| key.name == name)
+            || self.signing_keys.iter().any(|key| key.name == name)
+            || self.passwords.iter().any(|password| password.name == name);
+
+        match taken {
+            true => Err(Error::DuplicateKey(name.to_string())),
+            false => Ok(()),
+        }
+    }
+
+    /// Forgets the key or password called `name`. Returns `false` if there was none.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.symmetric_keys.len() + self.box_keys.len() + self.signing_keys.len() + self.passwords.len();
+
+        self.symmetric_keys.retain(|key| key.name != name);
+        self.box_keys.retain(|key| key.name != name);
+        self.signing_keys.retain(|key| key.name != name);
+        self.passwords.retain(|password| password.name != name);
+
+        before != self.symmetric_keys.len() + self.box_keys.len() + self.signing_keys.len() + self.passwords.len()
+    }
+
+    pub fn symmetric_key(&self, name: &str) -> Option<SecretKey> {
+        let key = self.symmetric_keys.iter().find(|key| key.name == name)?;
+
+        unlock(&key.key)
+    }
+
+    pub fn box_public_key(&self, name: &str) -> Option<&PublicKey> {
+        self.box_keys.iter().find(|key| key.name == name).map(|key| &key.public_key)
+    }
+
+    pub fn signing_public_key(&self, name: &str) -> Option<&SigningPublicKey> {
+        self.signing_keys.iter().find(|key| key.name == name).map(|key| &key.public_key)
+    }
+
+    /// The symmetric keys that may open `entry`: the one its `KEY_ID` names, or all of them if it
+    /// has no `KEY_ID`.
+    pub fn symmetric_keys_for(&self, entry: &Entry) -> Vec<SecretKey> {
+        let id = key_id_of(entry);
+
+        self.symmetric_keys
+            .iter()
+            .filter(|key| id.is_none_or(|id| *id == key.id))
+            .filter_map(|key| unlock(&key.key))
+            .collect()
+    }
+
+    /// Like [`symmetric_keys_for`](Self::symmetric_keys_for), for the secret keys of box key pairs.
+    pub fn secret_keys_for(&self, entry: &Entry) -> Vec<SecretKey> {
+        let id = key_id_of(entry);
+
+        self.box_keys
+            .iter()
+            .filter(|key| id.is_none_or(|id| *id == key.id))
+            .filter_map(|key| unlock(&key.secret_key))
+            .collect()
+    }
+
+    /// Every password, for boxes that can only be opened by trial.
+    pub fn passwords(&self) -> impl Iterator<Item=&[u8]> {
+        self.passwords.iter().map(|password| password.password.as_slice())
+    }
+
+    /// Seals `entry` with the symmetric key called `name`.
+    pub fn seal_symmetric(&self, entry: Entry, name: &str) -> Result<BurritoBoxSym> {
+        let key = self.symmetric_keys.iter().find(|key| key.name == name).ok_or_else(|| Error::UnknownKey(name.to_string()))?;
+
+        BurritoBoxSym::encrypt_sym(entry, unlock(&key.key).ok_or_else(|| Error::UnknownKey(name.to_string()))?)
+    }
+
+    /// Seals `entry` for the box key pair called `name`.
+    pub fn seal_for(&self, entry: Entry, name: &str) -> Result<BurritoBox> {
+        let key = self.box_keys.iter().find(|key| key.name == name).ok_or_else(|| Error::UnknownKey(name.to_string()))?;
+
+        BurritoBox::encrypt(entry, key.public_key.clone())
+    }
+
+    /// Opens any box the keyring has a key for: symmetric, AEAD, asymmetric or multi-recipient.
+    pub fn decrypt(&self, entry: Entry) -> Result<Entry> {
+        self.open(&entry)?.map(|(inner, _opener)| inner).ok_or(Error::NoMatchingKey)
+    }
+
+    /// Opens a box like [`decrypt`](Self::decrypt), and tells which key did. `None` if no key does.
+    pub(crate) fn open(&self, entry: &Entry) -> Result<Option<(Entry, Opener<'_>)>> {
+        let id = key_id_of(entry);
+        let waiter = get_str(entry, "waiter")?;
+
+        if waiter == BurritoBox::name() {
+            for key in self.box_keys.iter().filter(|key| id.is_none_or(|id| *id == key.id)) {
+                let Some(secret_key) = unlock(&key.secret_key) else { continue };
+
+                match BurritoBox::from_entry(entry.clone())?.decrypt(secret_key) {
+                    Err(Error::MacFailure | Error::KeyMismatch) => continue,
+                    result => return Ok(Some((result?, Opener::Box(&key.name)))),
+                }
+            }
+
+            return Ok(None);
+        }
+
+        if waiter == BurritoAeadBox::name() {
+            for key in self.symmetric_keys.iter().filter(|key| id.is_none_or(|id| *id == key.id)) {
+                let Some(secret_key) = unlock(&key.key) else { continue };
+
+                match BurritoAeadBox::from_entry(entry.clone())?.decrypt_sym(secret_key) {
+                    Err(Error::MacFailure | Error::KeyMismatch) => continue,
+                    result => return Ok(Some((result?, Opener::Aead(&key.name)))),
+                }
+            }
+
+            return Ok(None);
+        }
+
+        if waiter == BurritoMultiBox::name() {
+            // Multi-recipient boxes already list the ids of their recipients.
+            let sealed = BurritoMultiBox::from_entry(entry.clone())?;
+            let key = self.box_keys.iter().find(|key| sealed.recipient_ids().any(|id| id == key.id.bytes));
+
+            return match key.and_then(|key| Some((key, unlock(&key.secret_key)?))) {
+                Some((key, secret_key)) => Ok(Some((sealed.decrypt(secret_key)?, Opener::Multi(&key.name)))),
+                None => Ok(None),
+            };
+        }
+
+        if waiter == BurritoStreamBox::name() {
+            return Err(Error::DetachedCiphertext);
+        }
+
+        if waiter != BurritoBoxSym::name() {
+            return Err(Error::NonStandardBox(format!("{} is not a box", waiter)));
+        }
+
+        for key in self.symmetric_keys.iter().filter(|key| id.is_none_or(|id| *id == key.id)) {
+            let Some(secret_key) = unlock(&key.key) else { continue };
+
+            match BurritoBoxSym::from_entry(entry.clone())?.decrypt_sym(secret_key) {
+                Err(Error::MacFailure | Error::KeyMismatch) => continue,
+                result => return Ok(Some((result?, Opener::Symmetric(&key.name)))),
+            }
+        }
+
+        // The KEY_ID of a password box names the derived key, which no keyring holds.
+        if id.is_none() || entry.contains_key("KDF") {
+            for password in &self.passwords {
+                let sealed = BurritoBoxSym::from_entry(entry.clone())?;
+                let key = sealed.password_key(password.password.as_slice())?;
+
+                // Boxes sealed before the KDF was stored used the interactive preset.
+                let params = sealed.kdf.as_ref().map_or_else(KdfParams::interactive, |kdf| kdf.params.clone());
+
+                match sealed.decrypt_sym(key) {
+                    Err(Error::MacFailure | Error::KeyMismatch) => continue,
+                    result => return Ok(Some((result?, Opener::Password { name: &password.name, params }))),
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The names of the box key pairs `sealed` is for. Fails if a recipient has none in the keyring.
+    pub(crate) fn recipient_names(&self, sealed: &BurritoMultiBox) -> Result<Vec<&str>> {
+        sealed
+            .recipient_ids()
+            .map(|id| {
+                self.box_keys
+                    .iter()
+                    .find(|key| key.id.bytes == id)
+                    .map(|key| key.name.as_str())
+                    .ok_or(Error::UnknownRecipient)
+            })
+            .collect()
+    }
+
+    /// Signs with the signing key pair called `name`.
+    pub fn sign<T: Signing>(&self, signable: T, name: &str) -> Result<T> {
+        let key = self.signing_keys.iter().find(|key| key.name == name).ok_or_else(|| Error::UnknownKey(name.to_string()))?;
+
+        signable.sign(unlock(&key.secret_key).ok_or_else(|| Error::UnknownKey(name.to_string()))?)
+    }
+
+    /// Verifies a signature made by one of the keyring's signing keys, or a symmetric signature
+    /// made with one of its symmetric keys. Signatures by anyone else are rejected, even if valid.
+    pub fn verify<T: Signing + Clone>(&self, signed: T) -> Result<T> {
+        if let Some(Bson::Binary(public_key)) = signed.get_meta("signing_public_key") {
+            let id = public_key_id(&public_key.bytes)?;
+            let key = self.signing_keys.iter().find(|key| key.id == id).ok_or(Error::UntrustedSigner)?;
+
+            return signed.verify_with(key.public_key.clone());
+        }
+
+        if signed.get_meta("signature_sym").is_some() {
+            for (_name, key) in self.symmetric_signing_keys() {
+                match signed.clone().verify_sym(key) {
+                    Err(Error::InvalidSignature) => continue,
+                    result => return result,
+                }
+            }
+
+            return Err(Error::UntrustedSigner);
+        }
+
+        Err(Error::MissingSignature)
+    }
+
+    pub(crate) fn box_secret_key(&self, name: &str) -> Option<SecretKey> {
+        let key = self.box_keys.iter().find(|key| key.name == name)?;
+
+        unlock(&key.secret_key)
+    }
+
+    pub(crate) fn password(&self, name: &str) -> Option<&[u8]> {
+        self.passwords.iter().find(|password| password.name == name).map(|password| password.password.as_slice())
+    }
+
+    /// The name of the signing key pair of `public_key`.
+    pub(crate) fn signing_key_name(&self, public_key: &[u8]) -> Option<&str> {
+        self.signing_keys.iter().find(|key| key.public_key.as_slice() == public_key).map(|key| key.name.as_str())
+    }
+
+    pub(crate) fn signing_secret_key(&self, name: &str) -> Option<SigningSecretKey> {
+        let key = self.signing_keys.iter().find(|key| key.name == name)?;
+
+        unlock(&key.secret_key)
+    }
+
+    /// The names of the symmetric keys, with the keys as used by [`Signing::sign_sym`].
+    pub(crate) fn symmetric_signing_keys(&self) -> impl Iterator<Item=(&str, Key)> {
+        self.symmetric_keys
+            .iter()
+            .filter_map(|key| Some((key.name.as_str(), key_from_slice::<32, Key>(key.key.as_slice()).ok()?)))
+    }
+}
+
+/// Which key of a [`Keyring`] opened a box.
+pub(crate) enum Opener<'k> {
+    Symmetric(&'k str),
+    Box(&'k str),
+    /// With the cost of the KDF, to seal the box again the same way, with a fresh salt.
+    Password { name: &'k str, params: KdfParams },
+    Aead(&'k str),
+    /// The recipient the keyring opened the box as.
+    Multi(&'k str),
+}
+
+/// Whether `entry` is a box of any kind, including a `burrito_stream_box`, which no keyring opens.
+pub(crate) fn is_box(entry: &Entry) -> bool {
+    let Ok(waiter) = entry.get_str("waiter") else { return false };
+
+    [BurritoBoxSym::name(), BurritoBox::name(), BurritoAeadBox::name(), BurritoMultiBox::name(), BurritoStreamBox::name()]
+        .iter()
+        .any(|name| name == waiter)
+}
+
+fn key_id_of(entry: &Entry) -> Option<&bson::Binary> {
+    match entry.get(KEY_ID) {
+        Some(Bson::Binary(id)) => Some(id),
+        _ => None,
+    }
+}
+
+/// A short-lived, unlocked copy of a key, for the APIs that take keys by value.
+fn unlock<const N: usize, K>(locked: &Locked<dryoc::protected::HeapByteArray<N>>) -> Option<K>
+where
+    K: dryoc::types::ByteArray<N> + for<'a> TryFrom<&'a [u8]>,
+{
+    key_from_slice(locked.as_slice()).ok()
+}
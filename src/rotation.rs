@@ -0,0 +1,275 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+//! Rotating the keys of a vault: every box sealed again with new keys, every signature made again.
+
+use crate::database::Entry;
+use crate::error::{key_from_slice, Error, Result};
+use crate::history::continue_history;
+use crate::keyring::{is_box, Keyring, Opener};
+use crate::signing::{Signing, SIGNATURE_FIELDS};
+use crate::waiters::burrito_box_sym::BurritoBoxSym;
+use crate::waiters::burrito_multi_box::BurritoMultiBox;
+use crate::waiters::recursive::{segment_of, Recursive};
+use crate::waiters::Waiter;
+use crate::walker::{keep_metadata, seal_aead};
+use bson::Bson;
+use dryoc::sign::protected::PublicKey;
+
+/// A node [`rotate`] had to leave as it was, with everything below it.
+#[derive(Debug)]
+pub struct Unrotated {
+    /// Like [`Node::path`](crate::waiters::recursive::Node::path). `""` for the root.
+    pub path: Option<String>,
+    pub error: Error,
+}
+
+pub struct Rotation {
+    pub entry: Entry,
+    pub unrotated: Vec<Unrotated>,
+}
+
+impl Rotation {
+    /// Whether nothing is left sealed or signed with the old keys.
+    pub fn is_complete(&self) -> bool {
+        self.unrotated.is_empty()
+    }
+}
+
+/// Replaces the keys of a whole entry tree. Keys are matched by name: a box opened with the key
+/// called `work` in `old` is sealed with the key called `work` in `new`.
+///
+/// Every box that `old` opens is sealed again, with a fresh nonce (or ephemeral key), a fresh salt
+/// for password boxes, and its cleartext metadata. Multi-recipient boxes get a fresh content key,
+/// sealed for the new keys of every recipient. Every signature made with a key of `old` is checked, then made again with the key of
+/// the same name in `new`: signed boxes, signed groups whose children changed, and signed entries
+/// alike. Entries with a [history](crate::history) that changed get a new revision, signed with
+/// the new key named like the old key that signed the last revision.
+///
+/// Nodes that can't be rotated (no old key opens them, there is no new key of the same name, a
+/// recipient isn't in `old`, a signature or history is invalid, or by someone else on an entry that
+/// changed) are reported, and left as they were. So are stream boxes, whose ciphertext isn't in the
+/// entry.
+pub fn rotate(entry: Entry, old: &Keyring, new: &Keyring) -> Rotation {
+    let mut rotator = Rotator { old, new, unrotated: Vec::new() };
+    let (entry, _changed) = rotator.rotate_node(entry, Some(String::new()));
+
+    Rotation { entry, unrotated: rotator.unrotated }
+}
+
+struct Rotator<'k> {
+    old: &'k Keyring,
+    new: &'k Keyring,
+    unrotated: Vec<Unrotated>,
+}
+
+/// Who signed an entry, by the names of their keys.
+#[derive(Default)]
+struct Signers<'k> {
+    symmetric: Option<&'k str>,
+    asymmetric: Option<&'k str>,
+    security: Option<&'k str>,
+    co_signers: Vec<&'k str>,
+}
+
+impl Rotator<'_> {
+    fn rotate_node(&mut self, entry: Entry, path: Option<String>) -> (Entry, bool) {
+        match self.try_rotate(&entry, &path) {
+            Ok(rotated) => rotated,
+            Err(error) => {
+                self.unrotated.push(Unrotated { path, error });
+
+                (entry, false)
+            }
+        }
+    }
+
+    fn try_rotate(&mut self, entry: &Entry, path: &Option<String>) -> Result<(Entry, bool)> {
+        if let Some(sealed) = self.reseal(entry, path)? {
+            return Ok((self.resign(entry, sealed, true)?, true));
+        }
+
+        if Recursive::is_group(entry) {
+            let mut group = entry.clone();
+            let mut changed = false;
+
+            if let Ok(children) = group.get_array_mut("CHILDREN") {
+                for child in children.iter_mut() {
+                    let Bson::Document(document) = child else { continue };
+
+                    let child_path = path.as_ref().zip(segment_of(document)).map(|(parent, segment)| match parent.is_empty() {
+                        true => segment,
+                        false => format!("{}/{}", parent, segment),
+                    });
+                    let (rotated, child_changed) = self.rotate_node(std::mem::take(document), child_path);
+
+                    *document = rotated;
+                    changed |= child_changed;
+                }
+            }
+
+            let resigned = self.resign(entry, group, changed)?;
+            let changed = changed || resigned != *entry;
+
+            return Ok((resigned, changed));
+        }
+
+        let resigned = self.resign(entry, entry.clone(), false)?;
+        let changed = resigned != *entry;
+
+        Ok((resigned, changed))
+    }
+
+    /// Seals a box again with the new keys, once what is inside has been rotated. `None` if `entry`
+    /// is not a box.
+    fn reseal(&mut self, entry: &Entry, path: &Option<String>) -> Result<Option<Entry>> {
+        if !is_box(entry) {
+            return Ok(None);
+        }
+
+        let (inner, opener) = self.old.open(entry)?.ok_or(Error::NoMatchingKey)?;
+        let (inner, _changed) = self.rotate_node(inner, path.clone());
+        let unknown = |name: &str| Error::UnknownKey(name.to_string());
+
+        let mut sealed = match opener {
+            Opener::Symmetric(name) => self.new.seal_symmetric(inner, name)?.into_entry()?,
+            Opener::Box(name) => self.new.seal_for(inner, name)?.into_entry()?,
+            Opener::Password { name, params } => {
+                let password = self.new.password(name).ok_or_else(|| unknown(name))?;
+                BurritoBoxSym::of_secret_with(inner, password, params)?.into_entry()?
+            }
+            Opener::Aead(name) => seal_aead(entry, inner, self.new.symmetric_key(name).ok_or_else(|| unknown(name))?)?,
+            Opener::Multi(_name) => {
+                // The content key is replaced too, so the box can only be sealed again if every
+                // recipient has a new key.
+                let names = self.old.recipient_names(&BurritoMultiBox::from_entry(entry.clone())?)?;
+                let recipients = names
+                    .iter()
+                    .map(|name| self.new.box_public_key(name).cloned().ok_or_else(|| unknown(name)))
+                    .collect::<Result<Vec<_>>>()?;
+
+                BurritoMultiBox::encrypt_for(inner, &recipients)?.into_entry()?
+            }
+        };
+
+        keep_metadata(entry, &mut sealed);
+
+        Ok(Some(sealed))
+    }
+
+    /// Signs `updated` again with the new keys, if `original` was signed with old ones. If it
+    /// changed, and has a history, a revision signed with the new key of the last signer is added.
+    ///
+    /// Entries signed by anyone else are only a problem if they changed: the signature can't be
+    /// made again, and would no longer verify.
+    fn resign(&self, original: &Entry, updated: Entry, changed: bool) -> Result<Entry> {
+        let signers = match self.signers(original) {
+            Err(Error::UntrustedSigner) if !changed => return Ok(updated),
+            signers => signers?,
+        };
+
+        // Signatures cover the history, so it goes first.
+        let updated = match changed {
+            true => continue_history(updated, self.old, self.new)?,
+            false => updated,
+        };
+
+        if signers.symmetric.is_none() && signers.asymmetric.is_none() && signers.security.is_none() && signers.co_signers.is_empty() {
+            return Ok(updated);
+        }
+
+        let mut resigned = updated;
+        for field in SIGNATURE_FIELDS {
+            resigned.remove(*field);
+        }
+
+        // In the order the signatures are made: symmetric, then asymmetric, then the attestation.
+        // Co-signatures don't depend on the others.
+        if let Some(name) = signers.symmetric {
+            let (_name, key) = self
+                .new
+                .symmetric_signing_keys()
+                .find(|(candidate, _key)| *candidate == name)
+                .ok_or_else(|| Error::UnknownKey(name.to_string()))?;
+
+            resigned = resigned.sign_sym(key)?;
+        }
+
+        if let Some(name) = signers.asymmetric {
+            resigned = self.new.sign(resigned, name)?;
+        }
+
+        if let Some(name) = signers.security {
+            let key = self.new.signing_secret_key(name).ok_or_else(|| Error::UnknownKey(name.to_string()))?;
+
+            resigned = resigned.with_security(key)?;
+        }
+
+        for name in signers.co_signers {
+            let key = self.new.signing_secret_key(name).ok_or_else(|| Error::UnknownKey(name.to_string()))?;
+
+            resigned = resigned.add_signature(key)?;
+        }
+
+        Ok(resigned)
+    }
+
+    /// Checks every signature of `entry`, last made first, against the old keys.
+    fn signers(&self, entry: &Entry) -> Result<Signers<'_>> {
+        let mut signers = Signers::default();
+        let mut entry = entry.clone();
+
+        if entry.contains_key("assumed_secure") {
+            let (name, public_key) = self.signing_key(&entry, "security_signing_public_key")?;
+            if !entry.is_secure_with(public_key) {
+                return Err(Error::InvalidSignature);
+            }
+
+            entry.remove("assumed_secure");
+            entry.remove("security_signing_public_key");
+            signers.security = Some(name);
+        }
+
+        if entry.contains_key("signature") {
+            let (name, public_key) = self.signing_key(&entry, "signing_public_key")?;
+            entry = entry.verify_with(public_key)?;
+
+            entry.remove("signature");
+            entry.remove("signing_public_key");
+            signers.asymmetric = Some(name);
+        }
+
+        if entry.contains_key("signature_sym") {
+            let name = self
+                .old
+                .symmetric_signing_keys()
+                .find(|(_name, key)| entry.clone().verify_sym(key.clone()).is_ok())
+                .map(|(name, _key)| name)
+                .ok_or(Error::UntrustedSigner)?;
+
+            signers.symmetric = Some(name);
+        }
+
+        let content = entry.signed_content()?;
+        for signature in entry.co_signatures()? {
+            let name = self.old.signing_key_name(&signature.public_key.bytes).ok_or(Error::UntrustedSigner)?;
+            if !signature.verify(&content) {
+                return Err(Error::InvalidSignature);
+            }
+
+            signers.co_signers.push(name);
+        }
+
+        Ok(signers)
+    }
+
+    /// The name of the old signing key stored in `field`.
+    fn signing_key(&self, entry: &Entry, field: &str) -> Result<(&str, PublicKey)> {
+        let Some(Bson::Binary(public_key)) = entry.get(field) else { return Err(Error::MissingField(field.to_string())) };
+        let name = self.old.signing_key_name(&public_key.bytes).ok_or(Error::UntrustedSigner)?;
+
+        Ok((name, key_from_slice(&public_key.bytes)?))
+    }
+}
@@ -3,27 +3,54 @@
  *
  * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
  */
+use crate::canonical::to_canonical_vec;
 use crate::database::Metadata;
-use anyhow::bail;
+use crate::error::{key_from_slice, Error, Result};
 use bson::spec::BinarySubtype;
-use bson::Bson;
+use bson::{Bson, Document};
 use dryoc::auth::protected::Key;
 use dryoc::protected::HeapByteArray;
 use dryoc::sign::protected::{PublicKey, SecretKey};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+/// Every field written by [`Signing`]. None of them survive a change to the entry.
+pub const SIGNATURE_FIELDS: &[&str] = &[
+    "signature",
+    "signature_sym",
+    "signing_public_key",
+    "assumed_secure",
+    "security_signing_public_key",
+    "signatures",
+];
+
+/// One of the `signatures` of an entry signed by several people. See [`Signing::add_signature`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CoSignature {
+    pub public_key: bson::Binary,
+    pub signed: bson::DateTime,
+    pub signature: bson::Binary,
+}
+
+impl CoSignature {
+    /// Whether this signature is valid for `content` (see [`Signing::signed_content`]).
+    pub fn verify(&self, content: &Document) -> bool {
+        let Ok(public_key) = key_from_slice::<32, PublicKey>(&self.public_key.bytes) else { return false };
+        let Ok(message) = co_signed_message(content, self.signed) else { return false };
+
+        dryoc::sign::SignedMessage::from_parts(self.signature.bytes.clone(), message).verify(&public_key).is_ok()
+    }
+}
 
 pub trait Signing: Metadata + Serialize {
     /// symmetrical signature
-    fn sign_sym(self, secret_key: Key) -> Self
+    fn sign_sym(self, secret_key: Key) -> Result<Self>
     {
         use dryoc::auth::Auth;
         use dryoc::constants::CRYPTO_AUTH_BYTES;
 
-        let out = self.with_meta(("modified", bson::DateTime::now()));
+        let out = self.with_meta(("modified", bson::DateTime::now()))?;
 
-        // If the entries are not always in the same order, the signature will be different!!!!
-        // I used a BTreeMap internally to make sure the entries are always in the same order
-        let self_bytes = bson::to_vec(&out).expect("Failed to serialize entry");
+        let self_bytes = to_canonical_vec(&without_co_signatures(&out)?)?;
         let signature: [u8; CRYPTO_AUTH_BYTES] = Auth::compute(secret_key, &self_bytes);
         let signature = bson::Binary {
             subtype: BinarySubtype::Sensitive,
@@ -34,22 +61,19 @@ pub trait Signing: Metadata + Serialize {
             .with_meta(("signature_sym", signature))
     }
 
-    fn verify_sym(self, secret_key: Key) -> anyhow::Result<Self>
+    fn verify_sym(self, secret_key: Key) -> Result<Self>
     {
-        use dryoc::auth::Auth;
-
-        let mut self_entries = bson::to_document(&self)?;
-        let Some(Bson::Binary(signature)) = self_entries.remove("signature_sym") else { bail!("Entry does not contain valid signature") };
-        let signature = signature.bytes;
-        // If the entries are not always in the same order, the signature will be different!!!!
-        // I used a BTreeMap to make sure the entries are always in the same order
-        let self_bytes = bson::to_vec(&self_entries).expect("Failed to serialize entry");
-        Auth::compute_and_verify(&signature.as_slice(), secret_key, &self_bytes)?;
+        verify_sym_encoded(self, secret_key, Encodings::Canonical)
+    }
 
-        Ok(self)
+    /// Like [`verify_sym`](Self::verify_sym), but also accepts signatures made before signing was
+    /// canonical. Only use it to migrate old entries: sign them again once verified.
+    fn verify_sym_legacy(self, secret_key: Key) -> Result<Self>
+    {
+        verify_sym_encoded(self, secret_key, Encodings::CanonicalOrLegacy)
     }
 
-    fn sign(self, key: SecretKey) -> Self {
+    fn sign(self, key: SecretKey) -> Result<Self> {
         use dryoc::sign::SigningKeyPair;
         use dryoc::sign::protected::PublicKey;
         use dryoc::sign::protected::SecretKey;
@@ -61,11 +85,11 @@ pub trait Signing: Metadata + Serialize {
         };
 
         let out = self
-            .with_meta(("modified", bson::DateTime::now()))
-            .with_meta(("signing_public_key", public_key));
+            .with_meta(("modified", bson::DateTime::now()))?
+            .with_meta(("signing_public_key", public_key))?;
 
-        let self_bytes = bson::to_vec(&out).expect("Failed to serialize entry");
-        let (signature, _data): (HeapByteArray<64>, _) = keypair.sign(self_bytes).expect("Failed to sign entry").into_parts();
+        let self_bytes = to_canonical_vec(&without_co_signatures(&out)?)?;
+        let (signature, _data): (HeapByteArray<64>, _) = keypair.sign(self_bytes)?.into_parts();
         let signature = bson::Binary {
             subtype: BinarySubtype::Sensitive,
             bytes: signature.to_vec(),
@@ -76,36 +100,29 @@ pub trait Signing: Metadata + Serialize {
             .with_meta(("signature", signature))
     }
 
-    fn verify(self) -> anyhow::Result<Self> {
+    fn verify(self) -> Result<Self> {
         let self_entries = bson::to_document(&self)?;
 
-        let Some(Bson::Binary(public_key)) = self_entries.get_meta("signing_public_key") else { bail!("Entry does not contain valid signature") };
+        let Some(Bson::Binary(public_key)) = self_entries.get_meta("signing_public_key") else { return Err(Error::MissingSignature) };
         let public_key = &public_key.bytes;
-        let public_key = PublicKey::try_from(public_key.as_slice())?;
+        let public_key: PublicKey = key_from_slice(public_key.as_slice())?;
 
         self.verify_with(public_key)
     }
 
-    fn verify_with(self, public_key: PublicKey) -> anyhow::Result<Self> {
-        use dryoc::sign::SignedMessage;
-
-        let mut self_entries = bson::to_document(&self)?;
-
-        let Some(Bson::Binary(signature)) = self_entries.remove("signature") else { bail!("Entry does not contain valid signature") };
-        let signature = signature.bytes;
-
-
-        let self_signed = bson::to_vec(&self_entries)?;
-        let self_signed = SignedMessage::from_parts(signature, self_signed);
-
-        self_signed.verify(&public_key)?;
+    fn verify_with(self, public_key: PublicKey) -> Result<Self> {
+        verify_encoded(self, public_key, Encodings::Canonical)
+    }
 
-        Ok(self)
+    /// Like [`verify_with`](Self::verify_with), but also accepts signatures made before signing was
+    /// canonical. Only use it to migrate old entries: sign them again once verified.
+    fn verify_legacy(self, public_key: PublicKey) -> Result<Self> {
+        verify_encoded(self, public_key, Encodings::CanonicalOrLegacy)
     }
 
     const SECURITY_PADDING: &'static [u8] = b"This is some extra data to ensure that the signature is different, instead of being simply copy-pastable if the owner of the document did not also sign the document BEFORE adding a security attestation.";
 
-    fn with_security(self, key: SecretKey) -> Self {
+    fn with_security(self, key: SecretKey) -> Result<Self> {
         use dryoc::sign::SigningKeyPair;
         use dryoc::sign::protected::PublicKey;
         use dryoc::sign::protected::SecretKey;
@@ -117,12 +134,12 @@ pub trait Signing: Metadata + Serialize {
         };
 
         let out = self
-            .with_meta(("modified", bson::DateTime::now()))
-            .with_meta(("security_signing_public_key", public_key));
+            .with_meta(("modified", bson::DateTime::now()))?
+            .with_meta(("security_signing_public_key", public_key))?;
 
-        let mut self_bytes = bson::to_vec(&out).expect("Failed to serialize entry");
+        let mut self_bytes = to_canonical_vec(&without_co_signatures(&out)?)?;
         self_bytes.extend_from_slice(Self::SECURITY_PADDING);
-        let (signature, _data): (HeapByteArray<64>, _) = keypair.sign(self_bytes).expect("Failed to sign entry").into_parts();
+        let (signature, _data): (HeapByteArray<64>, _) = keypair.sign(self_bytes)?.into_parts();
         let signature = bson::Binary {
             subtype: BinarySubtype::Sensitive,
             bytes: signature.to_vec(),
@@ -137,26 +154,184 @@ pub trait Signing: Metadata + Serialize {
         let Ok(self_entries) = bson::to_document(&self) else { return false };
         let Some(Bson::Binary(public_key)) = self_entries.get("security_signing_public_key") else { return false };
         let public_key = &public_key.bytes;
-        let Ok(public_key) = PublicKey::try_from(public_key.as_slice()) else { return false };
+        let Ok(public_key) = key_from_slice::<_, PublicKey>(public_key.as_slice()) else { return false };
 
         self.is_secure_with(public_key)
     }
 
     fn is_secure_with(&self, public_key: PublicKey) -> bool {
-        use dryoc::sign::SignedMessage;
+        is_secure_encoded(self, public_key, Encodings::Canonical)
+    }
 
-        let Ok(mut self_entries) = bson::to_document(&self) else { return false };
-        let Some(Bson::Binary(signature)) = self_entries.remove("assumed_secure") else { return false };
-        let signature = signature.bytes;
+    /// Like [`is_secure_with`](Self::is_secure_with), but also accepts attestations made before
+    /// signing was canonical. Only use it to migrate old entries.
+    fn is_secure_legacy(&self, public_key: PublicKey) -> bool {
+        is_secure_encoded(self, public_key, Encodings::CanonicalOrLegacy)
+    }
 
-        let Ok(mut self_bytes) = bson::to_vec(&self_entries) else { return false };
-        self_bytes.extend_from_slice(Self::SECURITY_PADDING);
-        let self_signed = SignedMessage::from_parts(signature, self_bytes);
+    /// What co-signers sign: the entry without any signature, or `modified` (which every other kind
+    /// of signature updates). Every signer of an entry that hasn't changed signs the same content.
+    fn signed_content(&self) -> Result<Document> {
+        let mut content = bson::to_document(&self)?;
+        for field in SIGNATURE_FIELDS {
+            content.remove(*field);
+        }
+        content.remove("modified");
+
+        Ok(content)
+    }
+
+    /// The `signatures` of the entry. Entries without any have none.
+    fn co_signatures(&self) -> Result<Vec<CoSignature>> {
+        match self.get_meta("signatures") {
+            Some(signatures) => Ok(bson::from_bson(signatures.clone())?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Adds a signature to the `signatures` of the entry, next to those of other signers, for
+    /// [`verify_threshold`](Self::verify_threshold). Signing again with the same key replaces the
+    /// previous signature. Unlike [`sign`](Self::sign), this doesn't touch `modified`.
+    fn add_signature(self, key: SecretKey) -> Result<Self> {
+        use dryoc::sign::SigningKeyPair;
+        use dryoc::sign::protected::PublicKey;
+        use dryoc::sign::protected::SecretKey;
+
+        let keypair: SigningKeyPair<PublicKey, SecretKey> = SigningKeyPair::from_secret_key(key);
+        let public_key = bson::Binary {
+            subtype: BinarySubtype::Sensitive,
+            bytes: keypair.public_key.to_vec(),
+        };
+
+        let signed = bson::DateTime::now();
+        let message = co_signed_message(&self.signed_content()?, signed)?;
+        let (signature, _data): (HeapByteArray<64>, _) = keypair.sign(message)?.into_parts();
+
+        let mut signatures = self.co_signatures()?;
+        signatures.retain(|existing| existing.public_key.bytes != public_key.bytes);
+        signatures.push(CoSignature {
+            public_key,
+            signed,
+            signature: bson::Binary {
+                subtype: BinarySubtype::Sensitive,
+                bytes: signature.to_vec(),
+            },
+        });
+
+        self.with_meta(("signatures", signatures))
+    }
 
-        let Ok(()) = self_signed.verify(&public_key) else { return false };
+    /// Succeeds if at least `threshold` distinct keys out of `trusted_keys` signed the entry as it
+    /// is now. Signatures by other keys, and signatures over an older version, don't count. A
+    /// threshold of 0 is an error, rather than accepting entries nobody signed.
+    fn verify_threshold(self, trusted_keys: &[PublicKey], threshold: usize) -> Result<Self> {
+        use dryoc::types::Bytes;
 
-        true
+        if threshold == 0 {
+            return Err(Error::InvalidThreshold);
+        }
+
+        let content = self.signed_content()?;
+
+        let mut signers: Vec<&PublicKey> = Vec::new();
+        for signature in self.co_signatures()? {
+            let trusted = trusted_keys.iter().find(|key| key.as_slice() == signature.public_key.bytes);
+
+            if let Some(key) = trusted.filter(|key| !signers.contains(key)) {
+                if signature.verify(&content) {
+                    signers.push(key);
+                }
+            }
+        }
+
+        if signers.len() < threshold {
+            return Err(Error::ThresholdNotMet { required: threshold, found: signers.len() });
+        }
+
+        Ok(self)
+    }
+}
+
+impl<T: Metadata + Serialize> Signing for T {}
+
+/// Which encodings of a document a signature is checked against.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Encodings {
+    Canonical,
+    /// Also the document as it was serialized, as signed before signing was canonical. That
+    /// depends on the order fields were inserted in, so it is only accepted on request.
+    CanonicalOrLegacy,
+}
+
+fn signed_bytes(document: &Document, encodings: Encodings) -> Result<Vec<Vec<u8>>> {
+    let document = without_co_signatures(document)?;
+
+    match encodings {
+        Encodings::Canonical => Ok(vec![to_canonical_vec(&document)?]),
+        Encodings::CanonicalOrLegacy => Ok(vec![to_canonical_vec(&document)?, bson::to_vec(&document)?]),
+    }
+}
+
+fn verify_sym_encoded<T: Signing>(signed: T, secret_key: Key, encodings: Encodings) -> Result<T> {
+    use dryoc::auth::Auth;
+
+    let mut self_entries = bson::to_document(&signed)?;
+    let Some(Bson::Binary(signature)) = self_entries.remove("signature_sym") else { return Err(Error::MissingSignature) };
+    let signature = signature.bytes;
+
+    let verified = signed_bytes(&self_entries, encodings)?
+        .iter()
+        .any(|self_bytes| Auth::compute_and_verify(&signature.as_slice(), secret_key.clone(), self_bytes).is_ok());
+    if !verified {
+        return Err(Error::InvalidSignature);
     }
+
+    Ok(signed)
+}
+
+fn verify_encoded<T: Signing>(signed: T, public_key: PublicKey, encodings: Encodings) -> Result<T> {
+    use dryoc::sign::SignedMessage;
+
+    let mut self_entries = bson::to_document(&signed)?;
+
+    let Some(Bson::Binary(signature)) = self_entries.remove("signature") else { return Err(Error::MissingSignature) };
+    let signature = signature.bytes;
+
+    let verified = signed_bytes(&self_entries, encodings)?
+        .into_iter()
+        .any(|self_signed| SignedMessage::from_parts(signature.clone(), self_signed).verify(&public_key).is_ok());
+    if !verified {
+        return Err(Error::InvalidSignature);
+    }
+
+    Ok(signed)
+}
+
+fn is_secure_encoded<T: Signing>(entry: &T, public_key: PublicKey, encodings: Encodings) -> bool {
+    use dryoc::sign::SignedMessage;
+
+    let Ok(mut self_entries) = bson::to_document(entry) else { return false };
+    let Some(Bson::Binary(signature)) = self_entries.remove("assumed_secure") else { return false };
+    let signature = signature.bytes;
+
+    let Ok(encodings) = signed_bytes(&self_entries, encodings) else { return false };
+
+    encodings.into_iter().any(|mut self_bytes| {
+        self_bytes.extend_from_slice(T::SECURITY_PADDING);
+
+        SignedMessage::from_parts(signature.clone(), self_bytes).verify(&public_key).is_ok()
+    })
 }
 
-impl<T: Metadata + Serialize> Signing for T {}
\ No newline at end of file
+/// Single signatures don't cover the `signatures` of co-signers, so both kinds can be added in any order.
+fn without_co_signatures(value: &impl Serialize) -> Result<Document> {
+    let mut document = bson::to_document(value)?;
+    document.remove("signatures");
+
+    Ok(document)
+}
+
+/// What a [`CoSignature`] covers: the content, and when it was signed.
+fn co_signed_message(content: &Document, signed: bson::DateTime) -> Result<Vec<u8>> {
+    to_canonical_vec(&bson::doc! { "content": content, "signed": signed })
+}
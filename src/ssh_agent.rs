@@ -0,0 +1,381 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+//! An ssh-agent ([draft-miller-ssh-agent](https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent))
+//! serving the `ssh_key` entries of an unlocked vault.
+//!
+//! Point `SSH_AUTH_SOCK` at the socket given to [`SshAgent::serve`], and `ssh`, `ssh-add -l` and
+//! `git` use the keys without them ever touching `~/.ssh`.
+
+use crate::error::{Error, Result};
+use crate::vault::random_suffix;
+use crate::waiters::ssh_key::{put_string, SshKey, SshReader, ED25519};
+use std::fs::{DirBuilder, Permissions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENT_SUCCESS: u8 = 6;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
+const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
+
+/// The same limit OpenSSH uses.
+const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+/// How many staging directory names [`SshAgent::bind`] tries before giving up.
+const STAGING_ATTEMPTS: usize = 8;
+/// How long to wait after a failed `accept` before the next one.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(50);
+/// `EBADF`, the same on every Unix: the listener was closed.
+const EBADF: i32 = 9;
+
+/// Asked before a key signs anything. Gets the key and the data to sign; returning `false` refuses.
+pub type ConfirmCallback = Arc<dyn Fn(&SshKey, &[u8]) -> bool + Send + Sync>;
+
+/// Restrictions on how a key may be used once added, like `ssh-add -t` and `ssh-add -c`.
+#[derive(Clone, Default)]
+pub struct Constraints {
+    /// The key is forgotten after this long.
+    pub lifetime: Option<Duration>,
+    pub confirm: Option<ConfirmCallback>,
+}
+
+struct Identity {
+    key: SshKey,
+    expires: Option<Instant>,
+    confirm: Option<ConfirmCallback>,
+}
+
+#[derive(Default)]
+pub struct SshAgent {
+    identities: Mutex<Vec<Identity>>,
+}
+
+impl SshAgent {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, key: SshKey) -> Result<()> {
+        self.add_constrained(key, Constraints::default())
+    }
+
+    /// Serves `key` until it is removed or its lifetime runs out. Adding a key again replaces its
+    /// constraints.
+    pub fn add_constrained(&self, key: SshKey, constraints: Constraints) -> Result<()> {
+        if key.key_type != ED25519 || !key.has_private_key() {
+            return Err(Error::InvalidSshKey("only Ed25519 private keys can be served".to_string()));
+        }
+
+        let identity = Identity {
+            expires: constraints.lifetime.map(|lifetime| Instant::now() + lifetime),
+            confirm: constraints.confirm,
+            key,
+        };
+
+        let mut identities = self.identities();
+        identities.retain(|existing| existing.key.public_key != identity.key.public_key);
+        identities.push(identity);
+
+        Ok(())
+    }
+
+    /// Returns `false` if the key wasn't served.
+    pub fn remove(&self, public_blob: &[u8]) -> bool {
+        let mut identities = self.identities();
+        let before = identities.len();
+
+        identities.retain(|identity| identity.key.public_key.bytes != public_blob);
+
+        identities.len() != before
+    }
+
+    pub fn remove_all(&self) {
+        self.identities().clear();
+    }
+
+    /// The public halves of the keys currently served.
+    pub fn keys(&self) -> Vec<SshKey> {
+        self.identities()
+            .iter()
+            .filter_map(|identity| SshKey::from_public_blob(identity.key.public_key.bytes.clone(), &identity.key.comment).ok())
+            .collect()
+    }
+
+    /// Locks the identities, dropping expired ones on the way.
+    fn identities(&self) -> MutexGuard<'_, Vec<Identity>> {
+        let mut identities = self.identities.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let now = Instant::now();
+        identities.retain(|identity| identity.expires.is_none_or(|expires| expires > now));
+
+        identities
+    }
+
+    /// Binds the socket with [`bind`](Self::bind), then serves connections on it until an I/O
+    /// error. Every connection gets its own thread.
+    pub fn serve(self: Arc<Self>, path: impl AsRef<Path>) -> Result<()> {
+        self.serve_listener(Self::bind(path)?)
+    }
+
+    /// Binds a socket at `path` that only the current user can connect to.
+    ///
+    /// A stale socket at `path` is replaced, but anything else there is an error: it is never
+    /// deleted. The socket is bound in a private (0700) directory next to `path`, made 0600, and
+    /// only then moved into place, so no one else can connect in between.
+    pub fn bind(path: impl AsRef<Path>) -> Result<UnixListener> {
+        let path = path.as_ref();
+
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => return Err(Error::SshAgent(format!("{} exists and is not a socket", path.display()))),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let file_name = path.file_name().ok_or_else(|| Error::SshAgent(format!("{} is not a file path", path.display())))?;
+        let staging = Self::create_staging(path, file_name)?;
+        let staged = staging.join("agent.sock");
+
+        let listener = UnixListener::bind(&staged)
+            .and_then(|listener| std::fs::set_permissions(&staged, Permissions::from_mode(0o600)).map(|_| listener))
+            .and_then(|listener| std::fs::rename(&staged, path).map(|_| listener));
+        let _ = std::fs::remove_file(&staged);
+        let _ = std::fs::remove_dir(&staging);
+
+        Ok(listener?)
+    }
+
+    /// Creates `.{name}.{random}`, a private directory next to the socket. Leftovers of a crashed
+    /// agent are never reused: another name is tried.
+    fn create_staging(path: &Path, file_name: &std::ffi::OsStr) -> Result<PathBuf> {
+        let mut attempts = 1;
+
+        loop {
+            let mut staging_name = std::ffi::OsString::from(".");
+            staging_name.push(file_name);
+            staging_name.push(format!(".{}", random_suffix()));
+            let staging = path.with_file_name(staging_name);
+
+            match DirBuilder::new().mode(0o700).create(&staging) {
+                Err(err) if err.kind() == ErrorKind::AlreadyExists && attempts < STAGING_ATTEMPTS => attempts += 1,
+                created => return Ok(created.map(|_| staging)?),
+            }
+        }
+    }
+
+    /// Serves every connection to `listener`, each on its own thread.
+    ///
+    /// A failed `accept` (out of file descriptors, a client that gave up...) only costs that
+    /// connection: the agent waits a moment and carries on. Only errors that mean the listener
+    /// itself is unusable end it.
+    pub fn serve_listener(self: Arc<Self>, listener: UnixListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) if is_fatal_accept_error(&err) => return Err(err.into()),
+                Err(_) => {
+                    // Gives connections in flight a chance to close, rather than spinning.
+                    std::thread::sleep(ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+            let agent = Arc::clone(&self);
+
+            std::thread::spawn(move || {
+                // A client hanging up or sending garbage only ends its own connection.
+                let _ = agent.serve_connection(stream);
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Answers requests on `stream` until the client hangs up.
+    pub fn serve_connection(&self, mut stream: impl Read + Write) -> Result<()> {
+        loop {
+            let request = match read_message(&mut stream) {
+                Err(Error::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                request => request?,
+            };
+
+            let response = self.handle(&request);
+            write_message(&mut stream, &response)?;
+        }
+    }
+
+    /// Answers a single request (without its length prefix). Anything that goes wrong is reported
+    /// to the client as `SSH_AGENT_FAILURE`, as the protocol requires.
+    pub fn handle(&self, request: &[u8]) -> Vec<u8> {
+        self.try_handle(request).unwrap_or_else(|_| vec![SSH_AGENT_FAILURE])
+    }
+
+    fn try_handle(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = SshReader::new(request);
+
+        match reader.u8()? {
+            SSH_AGENTC_REQUEST_IDENTITIES => {
+                let keys = self.keys();
+
+                let mut response = vec![SSH_AGENT_IDENTITIES_ANSWER];
+                response.extend_from_slice(&(keys.len() as u32).to_be_bytes());
+                for key in keys {
+                    put_string(&mut response, &key.public_key.bytes);
+                    put_string(&mut response, key.comment.as_bytes());
+                }
+
+                Ok(response)
+            }
+            SSH_AGENTC_SIGN_REQUEST => {
+                let public_blob = reader.string()?;
+                let data = reader.string()?;
+                // Flags only select RSA hash algorithms, which don't apply to Ed25519.
+                let _flags = reader.u32()?;
+
+                let (key, confirm) = {
+                    let identities = self.identities();
+                    let identity = identities
+                        .iter()
+                        .find(|identity| identity.key.public_key.bytes == public_blob)
+                        .ok_or_else(|| Error::SshAgent("unknown key".to_string()))?;
+
+                    (identity.key.clone(), identity.confirm.clone())
+                };
+
+                // Not under the lock: confirmation may wait on the user for a while.
+                if let Some(confirm) = confirm {
+                    if !confirm(&key, data) {
+                        return Err(Error::SshAgent("signing refused".to_string()));
+                    }
+                }
+
+                let mut response = vec![SSH_AGENT_SIGN_RESPONSE];
+                put_string(&mut response, &key.sign_data(data)?);
+
+                Ok(response)
+            }
+            SSH_AGENTC_REMOVE_IDENTITY => {
+                let public_blob = reader.string()?;
+
+                if self.remove(public_blob) {
+                    Ok(vec![SSH_AGENT_SUCCESS])
+                } else {
+                    Err(Error::SshAgent("unknown key".to_string()))
+                }
+            }
+            SSH_AGENTC_REMOVE_ALL_IDENTITIES => {
+                self.remove_all();
+
+                Ok(vec![SSH_AGENT_SUCCESS])
+            }
+            other => Err(Error::SshAgent(format!("unsupported request {}", other))),
+        }
+    }
+}
+
+/// Whether a failed `accept` means the listener can't accept anything anymore: it was closed, or
+/// isn't listening. Anything else, like running out of file descriptors, passes.
+fn is_fatal_accept_error(err: &std::io::Error) -> bool {
+    err.kind() == ErrorKind::InvalidInput || err.raw_os_error() == Some(EBADF)
+}
+
+fn read_message(stream: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_MESSAGE_LEN {
+        return Err(Error::SshAgent(format!("bad message length {}", len)));
+    }
+
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message)?;
+
+    Ok(message)
+}
+
+fn write_message(stream: &mut impl Write, message: &[u8]) -> Result<()> {
+    stream.write_all(&(message.len() as u32).to_be_bytes())?;
+    stream.write_all(message)?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// The other side of the protocol, for talking to an [`SshAgent`] (or any other ssh-agent).
+pub struct AgentClient<S> {
+    stream: S,
+}
+
+impl AgentClient<UnixStream> {
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(UnixStream::connect(path)?))
+    }
+}
+
+impl<S: Read + Write> AgentClient<S> {
+    pub fn new(stream: S) -> Self {
+        Self { stream }
+    }
+
+    /// The public keys the agent holds.
+    pub fn identities(&mut self) -> Result<Vec<SshKey>> {
+        let response = self.request(&[SSH_AGENTC_REQUEST_IDENTITIES])?;
+        let mut reader = SshReader::new(&response);
+
+        if reader.u8()? != SSH_AGENT_IDENTITIES_ANSWER {
+            return Err(Error::SshAgent("request refused".to_string()));
+        }
+
+        let count = reader.u32()?;
+        let mut keys = Vec::new();
+        for _ in 0..count {
+            let public_blob = reader.string()?.to_vec();
+            let comment = String::from_utf8_lossy(reader.string()?).into_owned();
+
+            keys.push(SshKey::from_public_blob(public_blob, &comment)?);
+        }
+
+        Ok(keys)
+    }
+
+    /// Returns an SSH signature blob.
+    pub fn sign(&mut self, key: &SshKey, data: &[u8]) -> Result<Vec<u8>> {
+        let mut request = vec![SSH_AGENTC_SIGN_REQUEST];
+        put_string(&mut request, &key.public_key.bytes);
+        put_string(&mut request, data);
+        request.extend_from_slice(&0u32.to_be_bytes());
+
+        let response = self.request(&request)?;
+        let mut reader = SshReader::new(&response);
+
+        if reader.u8()? != SSH_AGENT_SIGN_RESPONSE {
+            return Err(Error::SshAgent("signing refused".to_string()));
+        }
+
+        Ok(reader.string()?.to_vec())
+    }
+
+    pub fn remove_all(&mut self) -> Result<()> {
+        match self.request(&[SSH_AGENTC_REMOVE_ALL_IDENTITIES])?.first() {
+            Some(&SSH_AGENT_SUCCESS) => Ok(()),
+            _ => Err(Error::SshAgent("request refused".to_string())),
+        }
+    }
+
+    fn request(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+        write_message(&mut self.stream, message)?;
+
+        read_message(&mut self.stream)
+    }
+}
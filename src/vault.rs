@@ -0,0 +1,260 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::database::Entry;
+use crate::encryption::{CompositeKey, EncryptionWaiterSymmetric};
+use crate::error::{Error, Result};
+use crate::waiters::burrito_box_sym::{BurritoBoxSym, KdfParams};
+use crate::waiters::Waiter;
+use dryoc::dryocbox::protected::SecretKey;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Every vault file starts with these bytes, so that we never try to decrypt something that isn't ours.
+pub const MAGIC: &[u8; 8] = b"BURRITO\0";
+
+/// The version of the file layout (header + body), not of any waiter.
+pub const FORMAT_VERSION: u32 = 1;
+
+const HEADER_LEN: usize = MAGIC.len() + size_of::<u32>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VaultHeader {
+    pub format_version: u32,
+}
+
+impl VaultHeader {
+    pub fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut out = [0u8; HEADER_LEN];
+        out[..MAGIC.len()].copy_from_slice(MAGIC);
+        out[MAGIC.len()..].copy_from_slice(&self.format_version.to_le_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(Error::InvalidVault("missing magic bytes".to_string()));
+        }
+
+        let mut version = [0u8; size_of::<u32>()];
+        version.copy_from_slice(&bytes[MAGIC.len()..HEADER_LEN]);
+        let format_version = u32::from_le_bytes(version);
+
+        if format_version != FORMAT_VERSION {
+            return Err(Error::UnsupportedFormat { expected: FORMAT_VERSION, found: format_version });
+        }
+
+        Ok(Self { format_version })
+    }
+}
+
+impl Default for VaultHeader {
+    fn default() -> Self {
+        Self { format_version: FORMAT_VERSION }
+    }
+}
+
+/// What it takes to unlock the root `burrito_symmetric_box` of a vault.
+#[derive(Clone)]
+pub enum Credentials {
+    Password(String),
+    Key(SecretKey),
+    Composite(CompositeKey),
+}
+
+impl Credentials {
+    /// `kdf` is only used by password and composite credentials.
+    pub fn seal(&self, entry: Entry, kdf: &KdfParams) -> Result<BurritoBoxSym> {
+        match self {
+            Credentials::Password(password) => BurritoBoxSym::of_password_with(entry, password.clone(), kdf.clone()),
+            Credentials::Key(key) => BurritoBoxSym::encrypt_sym(entry, key.clone()),
+            Credentials::Composite(key) => BurritoBoxSym::of_composite(entry, key, kdf.clone()),
+        }
+    }
+
+    pub fn unseal(&self, sealed: BurritoBoxSym) -> Result<Entry> {
+        match self {
+            Credentials::Password(password) => sealed.decrypt_password(password.clone()),
+            Credentials::Key(key) => sealed.decrypt_sym(key.clone()),
+            Credentials::Composite(key) => sealed.decrypt_composite(key),
+        }
+    }
+}
+
+/// A burrito database file.
+///
+/// On disk, a vault is the header followed by a single BSON document: the root entry, sealed
+/// inside a `burrito_symmetric_box`. In memory, the vault holds the *decrypted* root entry
+/// (usually a `burrito_recursive`), and seals it again with a fresh nonce on every save.
+pub struct Vault {
+    header: VaultHeader,
+    root: Entry,
+    credentials: Credentials,
+    kdf: KdfParams,
+}
+
+impl Vault {
+    pub fn new(root: Entry, credentials: Credentials) -> Self {
+        Self {
+            header: VaultHeader::default(),
+            root,
+            credentials,
+            kdf: KdfParams::default(),
+        }
+    }
+
+    pub fn open(path: impl AsRef<Path>, credentials: Credentials) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+
+        Self::from_bytes(&bytes, credentials)
+    }
+
+    pub fn from_bytes(bytes: &[u8], credentials: Credentials) -> Result<Self> {
+        let header = VaultHeader::from_bytes(bytes)?;
+
+        let sealed: Entry = bson::from_slice(&bytes[HEADER_LEN..])?;
+        let sealed = BurritoBoxSym::from_entry(sealed)?;
+        let kdf = sealed.kdf_params().cloned().unwrap_or_default();
+        let root = credentials.unseal(sealed)?;
+
+        Ok(Self {
+            header,
+            root,
+            credentials,
+            kdf,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let sealed = self.credentials.seal(self.root.clone(), &self.kdf)?.into_entry()?;
+
+        let mut out = Vec::from(self.header.to_bytes());
+        sealed.to_writer(&mut out)?;
+
+        Ok(out)
+    }
+
+    /// Writes the vault next to `path` first, and only then renames it over `path`. A crash
+    /// halfway through a save leaves the previous file untouched.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let bytes = self.to_bytes()?;
+
+        write_atomic(path.as_ref(), &bytes)
+    }
+
+    pub fn header(&self) -> VaultHeader {
+        self.header
+    }
+
+    pub fn root(&self) -> &Entry {
+        &self.root
+    }
+
+    pub fn root_mut(&mut self) -> &mut Entry {
+        &mut self.root
+    }
+
+    pub fn into_root(self) -> Entry {
+        self.root
+    }
+
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = credentials;
+    }
+
+    /// The password hash costs used on the next save. An opened vault keeps the costs it was saved
+    /// with, until they are raised here.
+    pub fn kdf(&self) -> &KdfParams {
+        &self.kdf
+    }
+
+    pub fn set_kdf(&mut self, kdf: KdfParams) {
+        self.kdf = kdf;
+    }
+
+    pub fn with_kdf(mut self, kdf: KdfParams) -> Self {
+        self.kdf = kdf;
+        self
+    }
+}
+
+/// How many temporary names [`write_atomic`] tries before giving up.
+const TEMP_ATTEMPTS: usize = 8;
+
+/// `.{name}.{random}.tmp`, next to `path`.
+fn temp_path(path: &Path) -> Result<PathBuf> {
+    let Some(file_name) = path.file_name() else {
+        return Err(Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Not a file path: {}", path.display()))));
+    };
+
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", random_suffix()));
+
+    Ok(path.with_file_name(temp_name))
+}
+
+/// 16 random bytes, hex-encoded. Names made with it don't collide with leftovers of a crashed
+/// process, whatever its PID, or with other threads.
+pub(crate) fn random_suffix() -> String {
+    dryoc::rng::randombytes_buf(16).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Writes `bytes` to a temporary file next to `path`, then renames it over `path`.
+///
+/// The temporary file is only readable by its owner (on Unix), and must not exist yet: a symlink
+/// planted there is never followed, and another name is tried.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let (temp, mut file) = create_temp(path)?;
+
+    let written = (|| -> Result<()> {
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        Ok(())
+    })();
+
+    if let Err(err) = written.and_then(|_| Ok(std::fs::rename(&temp, path)?)) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(err);
+    }
+
+    // Make the rename itself durable. Not every platform lets us open a directory, so this is best-effort.
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates a temporary file for `path`, trying another name while one is taken.
+fn create_temp(path: &Path) -> Result<(PathBuf, File)> {
+    let mut attempts = 1;
+
+    loop {
+        let temp = temp_path(path)?;
+
+        match create_private(&temp) {
+            Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::AlreadyExists && attempts < TEMP_ATTEMPTS => attempts += 1,
+            file => return Ok((temp, file?)),
+        }
+    }
+}
+
+/// Creates a new file only its owner can read (on Unix). Fails if anything, even a symlink, is
+/// already at `path`.
+#[cfg(unix)]
+pub(crate) fn create_private(path: &Path) -> Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    Ok(OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn create_private(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new().write(true).create_new(true).open(path)?)
+}
@@ -0,0 +1,161 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::canonical::to_canonical_vec;
+use crate::database::{Entry, Metadata};
+use crate::encryption::EncryptionWaiterSymmetric;
+use crate::error::{Error, Result};
+use crate::waiters::Waiter;
+use bson::spec::BinarySubtype;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Tag, XChaCha20Poly1305, XNonce};
+use dryoc::dryocbox::protected::SecretKey;
+use dryoc::types::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Fields that are *not* authenticated as associated data: the ciphertext and MAC themselves,
+/// `version`, which migrations rewrite, and the fields written by `Signing` and `History`, so that
+/// a sealed box can still be signed, and given a history, afterwards. Revisions are signed on their own.
+///
+/// Anyone can change these without making decryption fail. In particular, `modified` can't be
+/// trusted unless the box is signed (signatures cover it) and the signature has been verified.
+pub const UNAUTHENTICATED_FIELDS: &[&str] = &[
+    "ENCRYPTED",
+    "MAC",
+    "version",
+    "modified",
+    "signature",
+    "signature_sym",
+    "signing_public_key",
+    "assumed_secure",
+    "security_signing_public_key",
+    "signatures",
+    "revisions",
+];
+
+/// Like [`BurritoBoxSym`](crate::waiters::burrito_box_sym::BurritoBoxSym), but every cleartext field
+/// (`waiter`, `created`, `title`...) but `version` is bound to the ciphertext as associated data.
+/// Changing any of them after sealing makes decryption fail.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct BurritoAeadBox {
+    pub encrypted: bson::Binary,
+    pub mac: bson::Binary,
+    pub nonce: bson::Binary,
+    #[serde(flatten)]
+    pub additional_fields: BTreeMap<String, bson::Bson>,
+}
+
+impl BurritoAeadBox {
+    /// Seals `entry`, with `metadata` (titles, tags, uuid...) as authenticated cleartext fields.
+    ///
+    /// Metadata must be set here: adding it to the box afterwards breaks the MAC.
+    pub fn encrypt_with_meta(entry: Entry, key: SecretKey, metadata: BTreeMap<String, bson::Bson>) -> Result<Self> {
+        use dryoc::constants::CRYPTO_AEAD_XCHACHA20POLY1305_IETF_NPUBBYTES;
+
+        let nonce = dryoc::rng::randombytes_buf(CRYPTO_AEAD_XCHACHA20POLY1305_IETF_NPUBBYTES);
+
+        let mut sealed = Self {
+            encrypted: bson::Binary {
+                subtype: BinarySubtype::Encrypted,
+                bytes: bson::to_vec(&entry)?,
+            },
+            mac: bson::Binary {
+                subtype: BinarySubtype::Sensitive,
+                bytes: Vec::new(),
+            },
+            nonce: bson::Binary {
+                subtype: BinarySubtype::Sensitive,
+                bytes: nonce,
+            },
+            additional_fields: metadata,
+        }
+            .and_defaults::<Self>()?;
+
+        let associated_data = sealed.associated_data()?;
+        let cipher = XChaCha20Poly1305::new_from_slice(key.as_slice()).map_err(|_| Error::BadKeyLength { expected: 32, found: key.len() })?;
+        let tag = cipher
+            .encrypt_in_place_detached(XNonce::from_slice(&sealed.nonce.bytes), &associated_data, &mut sealed.encrypted.bytes)
+            .map_err(|_| Error::NonStandardBox("plaintext is too long".to_string()))?;
+
+        sealed.mac.bytes = tag.to_vec();
+
+        Ok(sealed)
+    }
+
+    /// The canonical encoding of every authenticated field.
+    pub fn associated_data(&self) -> Result<Vec<u8>> {
+        let mut fields = bson::to_document(self)?;
+        for field in UNAUTHENTICATED_FIELDS {
+            fields.remove(*field);
+        }
+
+        to_canonical_vec(&fields)
+    }
+}
+
+impl Waiter for BurritoAeadBox {
+    fn name() -> String {
+        "burrito_aead_box".to_string()
+    }
+
+    fn version() -> String {
+        "0.0.0".to_string()
+    }
+
+    fn into_entry(self) -> Result<Entry> {
+        bson::to_document(&self)?.and_defaults::<Self>()
+    }
+
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
+
+        let burrito = bson::from_document(entry)?;
+
+        Ok(burrito)
+    }
+}
+
+impl Metadata for BurritoAeadBox {
+    fn get_meta(&self, key: &str) -> Option<&bson::Bson> {
+        self.additional_fields.get(key)
+    }
+
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
+    }
+}
+
+impl EncryptionWaiterSymmetric for BurritoAeadBox {
+    fn encrypt_sym(entry: Entry, key: SecretKey) -> Result<Self> {
+        Self::encrypt_with_meta(entry, key, BTreeMap::new())
+    }
+
+    fn decrypt_sym(self, key: SecretKey) -> Result<Entry> {
+        use dryoc::constants::{CRYPTO_AEAD_XCHACHA20POLY1305_IETF_ABYTES, CRYPTO_AEAD_XCHACHA20POLY1305_IETF_NPUBBYTES};
+
+        if self.nonce.bytes.len() != CRYPTO_AEAD_XCHACHA20POLY1305_IETF_NPUBBYTES {
+            return Err(Error::invalid_field("NONCE", format!("expected {} bytes", CRYPTO_AEAD_XCHACHA20POLY1305_IETF_NPUBBYTES)));
+        }
+
+        if self.mac.bytes.len() != CRYPTO_AEAD_XCHACHA20POLY1305_IETF_ABYTES {
+            return Err(Error::invalid_field("MAC", format!("expected {} bytes", CRYPTO_AEAD_XCHACHA20POLY1305_IETF_ABYTES)));
+        }
+
+        let associated_data = self.associated_data()?;
+        let cipher = XChaCha20Poly1305::new_from_slice(key.as_slice()).map_err(|_| Error::BadKeyLength { expected: 32, found: key.len() })?;
+
+        let mut decrypted = self.encrypted.bytes;
+        cipher
+            .decrypt_in_place_detached(XNonce::from_slice(&self.nonce.bytes), &associated_data, &mut decrypted, Tag::from_slice(&self.mac.bytes))
+            .map_err(|_| Error::MacFailure)?;
+
+        let entry = bson::from_slice(&decrypted)?;
+
+        Ok(entry)
+    }
+}
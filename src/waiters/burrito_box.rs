@@ -4,10 +4,12 @@
  * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
  */
 use crate::database::{Entry, Metadata};
-use crate::encryption::EncryptionWaiter;
+use crate::encryption::{public_key_id, EncryptionWaiter};
+use crate::error::{field_from_slice, key_from_slice, Error, Result};
 use bson::doc;
 use bson::spec::BinarySubtype;
 use dryoc::dryocbox::protected::{PublicKey, SecretKey};
+use dryoc::types::Bytes;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use crate::waiters::Waiter;
@@ -18,15 +20,18 @@ pub struct BurritoBox {
     encrypted: bson::Binary,
     ephemeral_public_key: bson::Binary,
     mac: bson::Binary,
+    /// The [`public_key_id`] of the recipient. Missing from boxes sealed with [`from_encrypted`](Self::from_encrypted).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<bson::Binary>,
     #[serde(flatten)]
     additional_fields: BTreeMap<String, bson::Bson>,
 }
 
 impl BurritoBox {
-    pub fn from_encrypted(encrypted: dryoc::dryocbox::VecBox) -> Self {
+    pub fn from_encrypted(encrypted: dryoc::dryocbox::VecBox) -> Result<Self> {
         let (mac, encrypted, Some(key)) = encrypted.into_parts() else {
             // we always expect an ephemeral public key to be encoded.
-            panic!("encrypted box is non-standard");
+            return Err(Error::NonStandardBox("missing ephemeral public key".to_string()));
         };
 
         let encrypted = bson::Binary {
@@ -48,10 +53,15 @@ impl BurritoBox {
             encrypted,
             mac,
             ephemeral_public_key,
+            key_id: None,
             additional_fields: BTreeMap::new(),
         }
             .and_defaults::<Self>()
     }
+
+    pub fn key_id(&self) -> Option<&bson::Binary> {
+        self.key_id.as_ref()
+    }
 }
 
 impl Waiter for BurritoBox {
@@ -63,12 +73,12 @@ impl Waiter for BurritoBox {
         "0.0.0".to_string()
     }
 
-    fn into_entry(self) -> Entry {
-        bson::to_document(&self).unwrap().and_defaults::<Self>()
+    fn into_entry(self) -> Result<Entry> {
+        bson::to_document(&self)?.and_defaults::<Self>()
     }
 
-    fn from_entry(entry: Entry) -> anyhow::Result<Self> {
-        Self::verify_version(&entry)?;
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
 
         let burrito_box = bson::from_document(entry)?;
 
@@ -81,22 +91,26 @@ impl Metadata for BurritoBox {
         self.additional_fields.get(key)
     }
 
-    fn set_meta(&mut self, metadata: (&str, impl Serialize)) {
-        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1).unwrap());
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
     }
 }
 
 impl EncryptionWaiter for BurritoBox {
-    fn encrypt(entry: Entry, key: PublicKey) -> anyhow::Result<Self> {
+    fn encrypt(entry: Entry, key: PublicKey) -> Result<Self> {
         use dryoc::dryocbox::VecBox;
 
         let entry_bytes = bson::to_vec(&entry)?;
         let secret_box = VecBox::seal(&entry_bytes, &key)?;
 
-        Ok(Self::from_encrypted(secret_box))
+        let mut burrito_box = Self::from_encrypted(secret_box)?;
+        burrito_box.key_id = Some(public_key_id(key.as_slice())?);
+
+        Ok(burrito_box)
     }
 
-    fn decrypt(self, key: SecretKey) -> anyhow::Result<Entry> {
+    fn decrypt(self, key: SecretKey) -> Result<Entry> {
         use dryoc::dryocbox::VecBox;
         use dryoc::dryocbox::protected::SecretKey;
         use dryoc::dryocbox::PublicKey;
@@ -105,17 +119,23 @@ impl EncryptionWaiter for BurritoBox {
 
         let keypair: KeyPair<PublicKey, SecretKey> = KeyPair::from_secret_key(key);
 
+        if let Some(key_id) = &self.key_id {
+            if *key_id != public_key_id(keypair.public_key.as_slice())? {
+                return Err(Error::KeyMismatch);
+            }
+        }
+
         let mac = self.mac.bytes;
-        let mac = Mac::try_from(mac.as_slice())?;
+        let mac: Mac = field_from_slice("MAC", mac.as_slice())?;
 
         let encrypted = self.encrypted.bytes;
 
         let ephemeral_public_key = self.ephemeral_public_key.bytes;
-        let ephemeral_public_key = PublicKey::try_from(ephemeral_public_key.as_slice())?;
+        let ephemeral_public_key: PublicKey = key_from_slice(ephemeral_public_key.as_slice())?;
 
         let encrypted = VecBox::from_parts(mac, encrypted, Some(ephemeral_public_key));
 
-        let unencrypted = encrypted.unseal_to_vec(&keypair)?;
+        let unencrypted = encrypted.unseal_to_vec(&keypair).map_err(|_| Error::MacFailure)?;
         let unencrypted = bson::from_slice(&unencrypted)?;
 
 
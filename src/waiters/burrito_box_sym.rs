@@ -4,88 +4,187 @@
  * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
  */
 use crate::database::{Entry, Metadata};
-use crate::encryption::EncryptionWaiterSymmetric;
-use crate::waiters::Waiter;
+use crate::encryption::{symmetric_key_id, CompositeKey, EncryptionWaiterSymmetric, KeyFrom};
+use crate::error::{field_from_slice, Error, Result};
+use crate::waiters::{Migration, Waiter};
 use bson::spec::BinarySubtype;
 use dryoc::dryocbox::protected::SecretKey;
 use dryoc::dryocsecretbox::Nonce;
-use dryoc::types::NewByteArray;
+use dryoc::types::{MutBytes, NewByteArray};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KdfAlgorithm {
+    Argon2i13,
+    Argon2id13,
+}
+
+/// Cost parameters of the password hash that turns a password into the key of a box.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    pub opslimit: u64,
+    pub memlimit: u64,
+}
+
+impl KdfParams {
+    pub fn interactive() -> Self {
+        use dryoc::constants::{CRYPTO_PWHASH_MEMLIMIT_INTERACTIVE, CRYPTO_PWHASH_OPSLIMIT_INTERACTIVE};
+
+        Self {
+            algorithm: KdfAlgorithm::Argon2id13,
+            opslimit: CRYPTO_PWHASH_OPSLIMIT_INTERACTIVE,
+            memlimit: CRYPTO_PWHASH_MEMLIMIT_INTERACTIVE as u64,
+        }
+    }
+
+    pub fn moderate() -> Self {
+        use dryoc::constants::{CRYPTO_PWHASH_MEMLIMIT_MODERATE, CRYPTO_PWHASH_OPSLIMIT_MODERATE};
+
+        Self {
+            algorithm: KdfAlgorithm::Argon2id13,
+            opslimit: CRYPTO_PWHASH_OPSLIMIT_MODERATE,
+            memlimit: CRYPTO_PWHASH_MEMLIMIT_MODERATE as u64,
+        }
+    }
+
+    pub fn sensitive() -> Self {
+        use dryoc::constants::{CRYPTO_PWHASH_MEMLIMIT_SENSITIVE, CRYPTO_PWHASH_OPSLIMIT_SENSITIVE};
+
+        Self {
+            algorithm: KdfAlgorithm::Argon2id13,
+            opslimit: CRYPTO_PWHASH_OPSLIMIT_SENSITIVE,
+            memlimit: CRYPTO_PWHASH_MEMLIMIT_SENSITIVE as u64,
+        }
+    }
+
+    /// Fails if the costs are above those of [`sensitive`](Self::sensitive). They are read from the
+    /// file before anything is authenticated, so a crafted box could otherwise have an unlock burn
+    /// hours of CPU, or allocate terabytes.
+    pub fn validate(&self) -> Result<()> {
+        let max = Self::sensitive();
+
+        if self.opslimit > max.opslimit {
+            return Err(Error::invalid_field("OPSLIMIT", format!("must be at most {}", max.opslimit)));
+        }
+        if self.memlimit > max.memlimit {
+            return Err(Error::invalid_field("MEMLIMIT", format!("must be at most {}", max.memlimit)));
+        }
+
+        Ok(())
+    }
+
+    pub fn derive_key(&self, password: &[u8], salt: &[u8]) -> Result<SecretKey> {
+        use dryoc::classic::crypto_pwhash::{crypto_pwhash, PasswordHashAlgorithm};
+
+        self.validate()?;
+
+        let algorithm = match self.algorithm {
+            KdfAlgorithm::Argon2i13 => PasswordHashAlgorithm::Argon2i13,
+            KdfAlgorithm::Argon2id13 => PasswordHashAlgorithm::Argon2id13,
+        };
+        let memlimit = usize::try_from(self.memlimit).map_err(|err| Error::Kdf(err.to_string()))?;
+
+        let mut key = SecretKey::new_byte_array();
+        crypto_pwhash(key.as_mut_slice(), password, salt, self.opslimit, memlimit, algorithm)
+            .map_err(|err| Error::Kdf(err.to_string()))?;
+
+        Ok(key)
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self::interactive()
+    }
+}
+
+/// The password hash settings stored alongside a password-sealed box.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct Kdf {
+    #[serde(flatten)]
+    pub params: KdfParams,
+    pub salt: bson::Binary,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub struct BurritoBoxSym {
     pub encrypted: bson::Binary,
     pub mac: bson::Binary,
     pub nonce: bson::Binary,
+    /// Only present on boxes sealed with a password.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<Kdf>,
+    /// The [`symmetric_key_id`] of the key (for password boxes, of the derived key).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<bson::Binary>,
     #[serde(flatten)]
     pub additional_fields: BTreeMap<String, bson::Bson>,
 }
 
 impl BurritoBoxSym {
-    pub fn of_password(entry: Entry, mut password: String) -> Self {
-        use dryoc::dryocbox::protected::SecretKey;
-        use dryoc::dryocbox::protected::PublicKey;
-        use dryoc::keypair::KeyPair;
-        use dryoc::dryocsecretbox::VecBox;
+    pub fn of_password(entry: Entry, password: String) -> Result<Self> {
+        Self::of_password_with(entry, password, KdfParams::interactive())
+    }
 
-        let nonce = Nonce::gen();
-        let salt = nonce.to_vec();
-        let config = dryoc::pwhash::Config::interactive();
-        let keypair: KeyPair<PublicKey, SecretKey> = dryoc::pwhash::PwHash::derive_keypair(unsafe { password.as_mut_vec() }, salt, config).unwrap();
-        let secret_key = &keypair.secret_key;
+    pub fn of_password_with(entry: Entry, password: String, params: KdfParams) -> Result<Self> {
+        Self::of_secret_with(entry, password.as_bytes(), params)
+    }
 
-        let entry_bytes = bson::to_vec(&entry).unwrap();
-        let encrypted = VecBox::encrypt(&entry_bytes, &nonce, secret_key);
-        let (mac, encrypted) = encrypted.into_parts();
+    pub fn of_composite(entry: Entry, key: &CompositeKey, params: KdfParams) -> Result<Self> {
+        Self::of_secret_with(entry, &key.to_bytes()?, params)
+    }
 
-        let encrypted = bson::Binary {
-            subtype: BinarySubtype::Encrypted,
-            bytes: encrypted,
-        };
+    pub(crate) fn of_secret_with(entry: Entry, secret: &[u8], params: KdfParams) -> Result<Self> {
+        use dryoc::constants::CRYPTO_PWHASH_SALTBYTES;
 
-        let mac = bson::Binary {
-            subtype: BinarySubtype::Sensitive,
-            bytes: mac.to_vec(),
-        };
+        let salt = dryoc::rng::randombytes_buf(CRYPTO_PWHASH_SALTBYTES);
+        let key = params.derive_key(secret, &salt)?;
 
-        let nonce = bson::Binary {
-            subtype: BinarySubtype::Sensitive,
-            bytes: nonce.to_vec(),
-        };
+        let mut sealed = Self::encrypt_sym(entry, key)?;
+        sealed.kdf = Some(Kdf {
+            params,
+            salt: bson::Binary {
+                subtype: BinarySubtype::Sensitive,
+                bytes: salt,
+            },
+        });
 
-        Self {
-            encrypted,
-            mac,
-            nonce,
-            additional_fields: BTreeMap::new(),
-        }
-            .and_defaults::<Self>()
+        Ok(sealed)
     }
 
-    pub fn decrypt_password(self, mut password: String) -> anyhow::Result<Entry> {
-        use dryoc::dryocbox::protected::SecretKey;
-        use dryoc::dryocbox::protected::PublicKey;
-        use dryoc::keypair::KeyPair;
-        use dryoc::dryocsecretbox::VecBox;
-        use dryoc::dryocsecretbox::Mac;
-
-        let salt = self.nonce.bytes;
-        let nonce = Nonce::try_from(salt.as_slice())?;
+    pub fn key_id(&self) -> Option<&bson::Binary> {
+        self.key_id.as_ref()
+    }
 
+    pub fn kdf_params(&self) -> Option<&KdfParams> {
+        self.kdf.as_ref().map(|kdf| &kdf.params)
+    }
 
-        let config = dryoc::pwhash::Config::interactive();
-        let keypair: KeyPair<PublicKey, SecretKey> = dryoc::pwhash::PwHash::derive_keypair(unsafe { password.as_mut_vec() }, salt, config)?;
+    /// Derives the key of a password-sealed box, with whatever cost parameters it was sealed with.
+    pub fn password_key(&self, password: &[u8]) -> Result<SecretKey> {
+        match &self.kdf {
+            Some(kdf) => kdf.params.derive_key(password, &kdf.salt.bytes),
+            // Boxes written before the KDF was stored used the interactive preset, salted with the nonce.
+            None => KdfParams::interactive().derive_key(password, &self.nonce.bytes),
+        }
+    }
 
-        let mac = Mac::try_from(self.mac.bytes.as_slice())?;
+    pub fn decrypt_password(self, password: String) -> Result<Entry> {
+        let key = self.key_from(password.into_bytes())?;
 
-        let encrypted = VecBox::from_parts(mac, self.encrypted.bytes);
+        self.decrypt_sym(key)
+    }
 
-        let decrypted = encrypted.decrypt_to_vec(&nonce, &keypair.secret_key)?;
-        let entry = bson::from_slice(&decrypted)?;
+    pub fn decrypt_composite(self, key: &CompositeKey) -> Result<Entry> {
+        let key = self.password_key(&key.to_bytes()?)?;
 
-        Ok(entry)
+        self.decrypt_sym(key)
     }
 }
 
@@ -95,17 +194,21 @@ impl Waiter for BurritoBoxSym {
     }
 
     fn version() -> String {
-        "0.0.0".to_string()
+        "0.1.0".to_string()
+    }
+
+    fn migrations() -> Vec<Migration> {
+        // 0.1.0 added the optional `KDF` field; a box without it is still read the 0.0.0 way.
+        vec![Migration::new("^0.0.0", "0.1.0", Ok)]
     }
 
-    fn into_entry(self) -> Entry {
-        bson::to_document(&self)
-            .unwrap()
+    fn into_entry(self) -> Result<Entry> {
+        bson::to_document(&self)?
             .and_defaults::<Self>()
     }
 
-    fn from_entry(entry: Entry) -> anyhow::Result<Self> {
-        Self::verify_version(&entry)?;
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
 
         let burrito = bson::from_document(entry)?;
 
@@ -118,13 +221,14 @@ impl Metadata for BurritoBoxSym {
         self.additional_fields.get(key)
     }
 
-    fn set_meta(&mut self, metadata: (&str, impl Serialize)) {
-        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1).unwrap());
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
     }
 }
 
 impl EncryptionWaiterSymmetric for BurritoBoxSym {
-    fn encrypt_sym(entry: Entry, key: SecretKey) -> anyhow::Result<Self> {
+    fn encrypt_sym(entry: Entry, key: SecretKey) -> Result<Self> {
         use dryoc::dryocsecretbox::VecBox;
         use dryoc::dryocsecretbox::Nonce;
 
@@ -148,30 +252,42 @@ impl EncryptionWaiterSymmetric for BurritoBoxSym {
             bytes: nonce.to_vec(),
         };
 
-        Ok(
-            Self {
-                encrypted,
-                mac,
-                nonce,
-                additional_fields: BTreeMap::new(),
-            }
-                .and_defaults::<Self>()
-        )
+        Self {
+            encrypted,
+            mac,
+            nonce,
+            kdf: None,
+            key_id: Some(symmetric_key_id(&key)?),
+            additional_fields: BTreeMap::new(),
+        }
+            .and_defaults::<Self>()
     }
 
-    fn decrypt_sym(self, key: SecretKey) -> anyhow::Result<Entry> {
+    fn decrypt_sym(self, key: SecretKey) -> Result<Entry> {
         use dryoc::dryocsecretbox::VecBox;
         use dryoc::dryocsecretbox::Mac;
 
-        let mac = Mac::try_from(self.mac.bytes.as_slice())?;
+        if let Some(key_id) = &self.key_id {
+            if *key_id != symmetric_key_id(&key)? {
+                return Err(Error::KeyMismatch);
+            }
+        }
+
+        let mac: Mac = field_from_slice("MAC", self.mac.bytes.as_slice())?;
         let encrypted = self.encrypted.bytes;
-        let nonce = self.nonce.bytes;
+        let nonce: Nonce = field_from_slice("NONCE", self.nonce.bytes.as_slice())?;
 
         let encrypted = VecBox::from_parts(mac, encrypted);
-        let decrypted = encrypted.decrypt_to_vec(&nonce, &key)?;
+        let decrypted = encrypted.decrypt_to_vec(&nonce, &key).map_err(|_| Error::MacFailure)?;
 
         let entry = bson::from_slice(&decrypted)?;
 
         Ok(entry)
     }
+}
+
+impl KeyFrom for BurritoBoxSym {
+    fn key_from(&self, data: Vec<u8>) -> Result<SecretKey> {
+        self.password_key(&data)
+    }
 }
\ No newline at end of file
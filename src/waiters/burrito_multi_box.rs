@@ -0,0 +1,236 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::database::{Entry, Metadata};
+use crate::encryption::{public_key_id, EncryptionWaiter};
+use crate::error::{field_from_slice, Error, Result};
+use crate::waiters::Waiter;
+use bson::spec::BinarySubtype;
+use dryoc::dryocbox::protected::{PublicKey, SecretKey};
+use dryoc::types::{ByteArray, Bytes, MutBytes, NewByteArray};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct Recipient {
+    /// See [`recipient_id`].
+    pub recipient_id: bson::Binary,
+    /// The content key, in a libsodium sealed box for this recipient.
+    pub encrypted_key: bson::Binary,
+}
+
+/// The [`public_key_id`] of a recipient.
+pub fn recipient_id(public_key: &PublicKey) -> Result<bson::Binary> {
+    public_key_id(public_key.as_slice())
+}
+
+/// An asymmetric box for several people at once.
+///
+/// The payload is encrypted once, in a secretbox, with a random content key. That content key is
+/// then sealed to every recipient. Adding or removing a recipient only touches `RECIPIENTS`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct BurritoMultiBox {
+    pub encrypted: bson::Binary,
+    pub mac: bson::Binary,
+    pub nonce: bson::Binary,
+    pub recipients: Vec<Recipient>,
+    #[serde(flatten)]
+    pub additional_fields: BTreeMap<String, bson::Bson>,
+}
+
+impl BurritoMultiBox {
+    /// Fails on an empty list of recipients: nobody could ever open the box.
+    pub fn encrypt_for(entry: Entry, recipients: &[PublicKey]) -> Result<Self> {
+        if recipients.is_empty() {
+            return Err(Error::NoRecipients);
+        }
+
+        let content_key = SecretKey::gen();
+        let mut sealed = Self::seal_payload(entry, &content_key)?;
+
+        for public_key in recipients {
+            sealed.wrap_for(&content_key, public_key)?;
+        }
+
+        sealed.and_defaults::<Self>()
+    }
+
+    /// Seals `entry` for the same recipients, with the same content key and a fresh nonce.
+    /// `unlock` must be the secret key of a recipient. Cleartext metadata is not kept.
+    pub fn reseal(&self, unlock: &SecretKey, entry: Entry) -> Result<Self> {
+        let content_key = self.content_key(unlock)?;
+
+        let mut sealed = Self::seal_payload(entry, &content_key)?;
+        sealed.recipients = self.recipients.clone();
+
+        sealed.and_defaults::<Self>()
+    }
+
+    /// A box for `entry` with no recipients yet.
+    fn seal_payload(entry: Entry, content_key: &SecretKey) -> Result<Self> {
+        use dryoc::dryocsecretbox::{Nonce, VecBox};
+
+        let entry_bytes = bson::to_vec(&entry)?;
+        let nonce = Nonce::gen();
+        let (mac, encrypted) = VecBox::encrypt(&entry_bytes, &nonce, content_key).into_parts();
+
+        Ok(Self {
+            encrypted: bson::Binary {
+                subtype: BinarySubtype::Encrypted,
+                bytes: encrypted,
+            },
+            mac: bson::Binary {
+                subtype: BinarySubtype::Sensitive,
+                bytes: mac.to_vec(),
+            },
+            nonce: bson::Binary {
+                subtype: BinarySubtype::Sensitive,
+                bytes: nonce.to_vec(),
+            },
+            recipients: Vec::new(),
+            additional_fields: BTreeMap::new(),
+        })
+    }
+
+    pub fn recipient_ids(&self) -> impl Iterator<Item=&[u8]> {
+        self.recipients.iter().map(|recipient| recipient.recipient_id.bytes.as_slice())
+    }
+
+    pub fn is_recipient(&self, public_key: &PublicKey) -> Result<bool> {
+        let id = recipient_id(public_key)?;
+
+        Ok(self.recipients.iter().any(|recipient| recipient.recipient_id == id))
+    }
+
+    /// Gives `new_recipient` access. `unlock` must be the secret key of an existing recipient,
+    /// because the content key has to be unwrapped before it can be wrapped again.
+    pub fn add_recipient(&mut self, unlock: &SecretKey, new_recipient: &PublicKey) -> Result<()> {
+        if self.is_recipient(new_recipient)? {
+            return Ok(());
+        }
+
+        let content_key = self.content_key(unlock)?;
+
+        self.wrap_for(&content_key, new_recipient)
+    }
+
+    /// Takes away `public_key`'s copy of the content key. Returns `false` if it wasn't a recipient.
+    /// Fails if it is the last recipient: nobody could ever open the box again.
+    ///
+    /// This does not re-encrypt the payload: a former recipient who kept the content key can still
+    /// read this version of the box. Seal a new box if that matters.
+    pub fn remove_recipient(&mut self, public_key: &PublicKey) -> Result<bool> {
+        let id = recipient_id(public_key)?;
+        let before = self.recipients.len();
+
+        if self.recipients.iter().all(|recipient| recipient.recipient_id == id) && before > 0 {
+            return Err(Error::NoRecipients);
+        }
+
+        self.recipients.retain(|recipient| recipient.recipient_id != id);
+
+        Ok(self.recipients.len() != before)
+    }
+
+    fn wrap_for(&mut self, content_key: &SecretKey, public_key: &PublicKey) -> Result<()> {
+        use dryoc::classic::crypto_box::crypto_box_seal;
+        use dryoc::constants::CRYPTO_BOX_SEALBYTES;
+
+        let mut encrypted_key = vec![0u8; content_key.len() + CRYPTO_BOX_SEALBYTES];
+        crypto_box_seal(&mut encrypted_key, content_key.as_slice(), public_key.as_array())?;
+
+        self.recipients.push(Recipient {
+            recipient_id: recipient_id(public_key)?,
+            encrypted_key: bson::Binary {
+                subtype: BinarySubtype::Encrypted,
+                bytes: encrypted_key,
+            },
+        });
+
+        Ok(())
+    }
+
+    fn content_key(&self, secret_key: &SecretKey) -> Result<SecretKey> {
+        use dryoc::classic::crypto_box::crypto_box_seal_open;
+        use dryoc::constants::CRYPTO_BOX_SEALBYTES;
+        use dryoc::keypair::KeyPair;
+
+        let keypair: KeyPair<PublicKey, SecretKey> = KeyPair::from_secret_key(secret_key.clone());
+        let id = recipient_id(&keypair.public_key)?;
+
+        let Some(recipient) = self.recipients.iter().find(|recipient| recipient.recipient_id == id) else {
+            return Err(Error::NotARecipient);
+        };
+
+        // Keys are only ever in protected memory, which is zeroed when dropped.
+        let mut content_key = SecretKey::new_byte_array();
+        let encrypted_key = &recipient.encrypted_key.bytes;
+        if encrypted_key.len() != content_key.len() + CRYPTO_BOX_SEALBYTES {
+            return Err(Error::invalid_field("ENCRYPTED_KEY", format!("expected {} bytes", content_key.len() + CRYPTO_BOX_SEALBYTES)));
+        }
+
+        crypto_box_seal_open(content_key.as_mut_slice(), encrypted_key, keypair.public_key.as_array(), keypair.secret_key.as_array())
+            .map_err(|_| Error::MacFailure)?;
+
+        Ok(content_key)
+    }
+}
+
+impl Waiter for BurritoMultiBox {
+    fn name() -> String {
+        "burrito_multi_recipient_box".to_string()
+    }
+
+    fn version() -> String {
+        "0.0.0".to_string()
+    }
+
+    fn into_entry(self) -> Result<Entry> {
+        bson::to_document(&self)?.and_defaults::<Self>()
+    }
+
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
+
+        let burrito = bson::from_document(entry)?;
+
+        Ok(burrito)
+    }
+}
+
+impl Metadata for BurritoMultiBox {
+    fn get_meta(&self, key: &str) -> Option<&bson::Bson> {
+        self.additional_fields.get(key)
+    }
+
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
+    }
+}
+
+impl EncryptionWaiter for BurritoMultiBox {
+    fn encrypt(entry: Entry, key: PublicKey) -> Result<Self> {
+        Self::encrypt_for(entry, &[key])
+    }
+
+    fn decrypt(self, key: SecretKey) -> Result<Entry> {
+        use dryoc::dryocsecretbox::{Mac, Nonce, VecBox};
+
+        let content_key = self.content_key(&key)?;
+
+        let mac: Mac = field_from_slice("MAC", self.mac.bytes.as_slice())?;
+        let nonce: Nonce = field_from_slice("NONCE", self.nonce.bytes.as_slice())?;
+
+        let encrypted = VecBox::from_parts(mac, self.encrypted.bytes);
+        let decrypted = encrypted.decrypt_to_vec(&nonce, &content_key).map_err(|_| Error::MacFailure)?;
+
+        let entry = bson::from_slice(&decrypted)?;
+
+        Ok(entry)
+    }
+}
@@ -0,0 +1,169 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::database::{Entry, Metadata};
+use crate::error::{field_from_slice, Error, Result};
+use crate::waiters::Waiter;
+use bson::spec::BinarySubtype;
+use dryoc::constants::CRYPTO_SECRETSTREAM_XCHACHA20POLY1305_ABYTES;
+use dryoc::dryocbox::protected::SecretKey;
+use dryoc::dryocstream::{DryocStream, Header, Tag};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+/// `CHUNK_SIZE` isn't authenticated, and a chunk is read into memory whole before its MAC is
+/// checked, so larger chunks are rejected.
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// The key and framing of a libsodium secretstream (XChaCha20-Poly1305), for payloads that are too
+/// large to be read into memory: attachments, key stores, PDFs...
+///
+/// The entry only holds the stream header. The ciphertext itself is written to a separate
+/// [`Write`], as a sequence of chunks of `CHUNK_SIZE + 17` bytes. Only the last chunk may be
+/// shorter, and it is tagged as final, so a stream cut short never decrypts successfully.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct BurritoStreamBox {
+    pub header: bson::Binary,
+    pub chunk_size: i64,
+    #[serde(flatten)]
+    pub additional_fields: BTreeMap<String, bson::Bson>,
+}
+
+impl BurritoStreamBox {
+    pub fn encrypt_stream(reader: impl Read, writer: impl Write, key: &SecretKey) -> Result<Self> {
+        Self::encrypt_stream_with(reader, writer, key, DEFAULT_CHUNK_SIZE)
+    }
+
+    pub fn encrypt_stream_with(mut reader: impl Read, mut writer: impl Write, key: &SecretKey, chunk_size: usize) -> Result<Self> {
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(Error::invalid_field("CHUNK_SIZE", format!("must be between 1 and {} bytes", MAX_CHUNK_SIZE)));
+        }
+
+        let (mut stream, header): (_, Header) = DryocStream::init_push(key);
+
+        let mut chunk = vec![0u8; chunk_size];
+        loop {
+            let read = read_full(&mut reader, &mut chunk)?;
+
+            // A short read means EOF. If the input ends exactly on a chunk boundary, the final
+            // chunk is simply empty.
+            let tag = if read < chunk_size { Tag::FINAL } else { Tag::MESSAGE };
+            let encrypted = stream.push_to_vec(&&chunk[..read], None, tag)?;
+            writer.write_all(&encrypted)?;
+
+            if tag == Tag::FINAL {
+                break;
+            }
+        }
+
+        writer.flush()?;
+
+        Self {
+            header: bson::Binary {
+                subtype: BinarySubtype::Sensitive,
+                bytes: header.to_vec(),
+            },
+            chunk_size: chunk_size as i64,
+            additional_fields: BTreeMap::new(),
+        }
+            .and_defaults::<Self>()
+    }
+
+    /// Decrypts the stream read from `reader` into `writer`, returning how many bytes were written.
+    ///
+    /// `writer` may have received part of the plaintext by the time an error is returned, so write
+    /// to a temporary location and only keep the output if this succeeds.
+    pub fn decrypt_stream(&self, mut reader: impl Read, mut writer: impl Write, key: &SecretKey) -> Result<u64> {
+        let header: Header = field_from_slice("HEADER", &self.header.bytes)?;
+        let chunk_size = usize::try_from(self.chunk_size)
+            .ok()
+            .filter(|size| *size > 0 && *size <= MAX_CHUNK_SIZE)
+            .ok_or_else(|| Error::invalid_field("CHUNK_SIZE", format!("must be between 1 and {} bytes", MAX_CHUNK_SIZE)))?;
+
+        let mut stream = DryocStream::init_pull(key, &header);
+
+        let mut chunk = vec![0u8; chunk_size + CRYPTO_SECRETSTREAM_XCHACHA20POLY1305_ABYTES];
+        let mut written = 0u64;
+        loop {
+            let read = read_full(&mut reader, &mut chunk)?;
+            if read < CRYPTO_SECRETSTREAM_XCHACHA20POLY1305_ABYTES {
+                return Err(Error::Truncated);
+            }
+
+            let (decrypted, tag) = stream.pull_to_vec(&&chunk[..read], None).map_err(|_| Error::MacFailure)?;
+            writer.write_all(&decrypted)?;
+            written += decrypted.len() as u64;
+
+            if tag == Tag::FINAL {
+                break;
+            }
+
+            if read < chunk.len() {
+                // Only the final chunk may be short.
+                return Err(Error::Truncated);
+            }
+        }
+
+        if read_full(&mut reader, &mut [0u8; 1])? != 0 {
+            return Err(Error::NonStandardBox("data after the final chunk".to_string()));
+        }
+
+        writer.flush()?;
+
+        Ok(written)
+    }
+}
+
+/// Fills `buf` as far as possible, stopping early only at EOF.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(filled)
+}
+
+impl Waiter for BurritoStreamBox {
+    fn name() -> String {
+        "burrito_stream_box".to_string()
+    }
+
+    fn version() -> String {
+        "0.0.0".to_string()
+    }
+
+    fn into_entry(self) -> Result<Entry> {
+        bson::to_document(&self)?.and_defaults::<Self>()
+    }
+
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
+
+        let burrito = bson::from_document(entry)?;
+
+        Ok(burrito)
+    }
+}
+
+impl Metadata for BurritoStreamBox {
+    fn get_meta(&self, key: &str) -> Option<&bson::Bson> {
+        self.additional_fields.get(key)
+    }
+
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
+    }
+}
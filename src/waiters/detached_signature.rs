@@ -0,0 +1,219 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::canonical::to_canonical_vec;
+use crate::database::{Entry, Metadata};
+use crate::error::{key_from_slice, Error, Result};
+use crate::waiters::Waiter;
+use bson::spec::BinarySubtype;
+use dryoc::sign::protected::{PublicKey, SecretKey};
+use dryoc::sign::{SignedMessage, SigningKeyPair};
+use dryoc::types::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+
+/// The only hash used so far.
+pub const SHA256: &str = "sha256";
+
+/// A signature over a whole file (usually a vault), kept next to it instead of inside it, so the
+/// file can be checked before it is even parsed.
+///
+/// The Ed25519 signature covers the canonical encoding of this document without its `SIGNATURE`,
+/// which includes the hash and size of the file, and any metadata set before signing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct DetachedSignature {
+    pub hash_algorithm: String,
+    pub file_hash: bson::Binary,
+    pub file_size: i64,
+    pub signing_public_key: bson::Binary,
+    pub signed: bson::DateTime,
+    pub signature: bson::Binary,
+    #[serde(flatten)]
+    pub additional_fields: BTreeMap<String, bson::Bson>,
+}
+
+impl DetachedSignature {
+    pub fn sign_file(path: impl AsRef<Path>, key: SecretKey) -> Result<Self> {
+        Self::sign_reader(std::fs::File::open(path)?, key)
+    }
+
+    pub fn sign_bytes(bytes: &[u8], key: SecretKey) -> Result<Self> {
+        Self::sign_reader(bytes, key)
+    }
+
+    pub fn sign_reader(reader: impl Read, key: SecretKey) -> Result<Self> {
+        Self::unsigned(reader)?.sign_with(key)
+    }
+
+    /// The manifest of the contents of `reader`, for metadata to be set on it before [`sign_with`](Self::sign_with).
+    pub fn unsigned(reader: impl Read) -> Result<Self> {
+        let (file_hash, file_size) = hash(reader)?;
+
+        Self {
+            hash_algorithm: SHA256.to_string(),
+            file_hash: bson::Binary {
+                subtype: BinarySubtype::Generic,
+                bytes: file_hash,
+            },
+            file_size,
+            signing_public_key: empty_binary(),
+            signed: bson::DateTime::now(),
+            signature: empty_binary(),
+            additional_fields: BTreeMap::new(),
+        }
+            .and_defaults::<Self>()
+    }
+
+    pub fn sign_with(mut self, key: SecretKey) -> Result<Self> {
+        let keypair: SigningKeyPair<PublicKey, SecretKey> = SigningKeyPair::from_secret_key(key);
+
+        self.signing_public_key = bson::Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: keypair.public_key.to_vec(),
+        };
+        self.signed = bson::DateTime::now();
+
+        let (signature, _data): (Vec<u8>, Vec<u8>) = keypair.sign(self.signed_bytes()?)?.into_parts();
+        self.signature = bson::Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: signature,
+        };
+
+        Ok(self)
+    }
+
+    pub fn verify_file(&self, path: impl AsRef<Path>, trusted: &PublicKey) -> Result<()> {
+        let path = path.as_ref();
+
+        // Don't read a file of the wrong size.
+        if std::fs::metadata(path)?.len() != self.file_size as u64 {
+            self.verify_manifest(trusted)?;
+            return Err(Error::FileMismatch);
+        }
+
+        self.verify_reader(std::fs::File::open(path)?, trusted)
+    }
+
+    pub fn verify_bytes(&self, bytes: &[u8], trusted: &PublicKey) -> Result<()> {
+        self.verify_reader(bytes, trusted)
+    }
+
+    /// Checks that this manifest was signed by `trusted`, then that it describes the contents of `reader`.
+    pub fn verify_reader(&self, reader: impl Read, trusted: &PublicKey) -> Result<()> {
+        self.verify_manifest(trusted)?;
+
+        if self.hash_algorithm != SHA256 {
+            return Err(Error::invalid_field("HASH_ALGORITHM", format!("unsupported hash {}", self.hash_algorithm)));
+        }
+
+        let (file_hash, file_size) = hash(reader)?;
+        if file_hash != self.file_hash.bytes || file_size != self.file_size {
+            return Err(Error::FileMismatch);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the signature over the manifest alone.
+    pub fn verify_manifest(&self, trusted: &PublicKey) -> Result<()> {
+        if self.signing_public_key.bytes != trusted.as_slice() {
+            return Err(Error::UntrustedSigner);
+        }
+
+        let public_key: PublicKey = key_from_slice(&self.signing_public_key.bytes)?;
+        SignedMessage::from_parts(self.signature.bytes.clone(), self.signed_bytes()?)
+            .verify(&public_key)
+            .map_err(|_| Error::InvalidSignature)
+    }
+
+    pub fn signer(&self) -> Result<PublicKey> {
+        key_from_slice(&self.signing_public_key.bytes)
+    }
+
+    pub fn to_bytes(self) -> Result<Vec<u8>> {
+        Ok(bson::to_vec(&self.into_entry()?)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_entry(bson::from_slice(bytes)?)
+    }
+
+    pub fn save(self, path: impl AsRef<Path>) -> Result<()> {
+        crate::vault::write_atomic(path.as_ref(), &self.to_bytes()?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        let mut document = bson::to_document(self)?;
+        document.remove("SIGNATURE");
+
+        to_canonical_vec(&document)
+    }
+}
+
+fn empty_binary() -> bson::Binary {
+    bson::Binary {
+        subtype: BinarySubtype::Generic,
+        bytes: Vec::new(),
+    }
+}
+
+fn hash(mut reader: impl Read) -> Result<(Vec<u8>, i64)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut size = 0;
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+        size += read as i64;
+    }
+
+    Ok((hasher.finalize().to_vec(), size))
+}
+
+impl Waiter for DetachedSignature {
+    fn name() -> String {
+        "burrito_detached_signature".to_string()
+    }
+
+    fn version() -> String {
+        "0.0.0".to_string()
+    }
+
+    fn into_entry(self) -> Result<Entry> {
+        bson::to_document(&self)?.and_defaults::<Self>()
+    }
+
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
+
+        let signature = bson::from_document(entry)?;
+
+        Ok(signature)
+    }
+}
+
+impl Metadata for DetachedSignature {
+    fn get_meta(&self, key: &str) -> Option<&bson::Bson> {
+        self.additional_fields.get(key)
+    }
+
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::database::{Entry, Metadata};
+use crate::error::Result;
+use crate::waiters::Waiter;
+use bson::spec::BinarySubtype;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A random secret kept in its own file, to be combined with a password in a `CompositeKey`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct KeyFile {
+    pub key: bson::Binary,
+    #[serde(flatten)]
+    pub additional_fields: BTreeMap<String, bson::Bson>,
+}
+
+impl KeyFile {
+    pub fn generate() -> Self {
+        let key = bson::Binary {
+            subtype: BinarySubtype::Sensitive,
+            bytes: dryoc::rng::randombytes_buf(32),
+        };
+
+        Self {
+            key,
+            additional_fields: BTreeMap::new(),
+        }
+    }
+
+    pub fn to_bytes(self) -> Result<Vec<u8>> {
+        Ok(bson::to_vec(&self.into_entry()?)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_entry(bson::from_slice(bytes)?)
+    }
+
+    pub fn save(self, path: impl AsRef<Path>) -> Result<()> {
+        crate::vault::write_atomic(path.as_ref(), &self.to_bytes()?)
+    }
+}
+
+impl Waiter for KeyFile {
+    fn name() -> String {
+        "burrito_key_file".to_string()
+    }
+
+    fn version() -> String {
+        "0.0.0".to_string()
+    }
+
+    fn into_entry(self) -> Result<Entry> {
+        bson::to_document(&self)?.and_defaults::<Self>()
+    }
+
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
+
+        let key_file = bson::from_document(entry)?;
+
+        Ok(key_file)
+    }
+}
+
+impl Metadata for KeyFile {
+    fn get_meta(&self, key: &str) -> Option<&bson::Bson> {
+        self.additional_fields.get(key)
+    }
+
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
+    }
+}
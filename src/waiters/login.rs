@@ -0,0 +1,186 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::database::{Entry, Metadata};
+use crate::error::Result;
+use crate::waiters::Waiter;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// How a saved URL is compared against the page a client wants to fill in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum UrlMatch {
+    /// Same host, or any subdomain of it. `example.com` matches `login.example.com`.
+    #[default]
+    Domain,
+    /// Same host only.
+    Host,
+    /// The URL starts with the saved one, which must end at a `/`, `?` or `#` of the URL.
+    /// `https://example.com` matches `https://example.com/login`, not `https://example.com.evil.net`.
+    StartsWith,
+    /// The URL is exactly the saved one.
+    Exact,
+    /// Never offered automatically.
+    Never,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct LoginUrl {
+    pub url: String,
+    #[serde(rename = "MATCH", default)]
+    pub match_rule: UrlMatch,
+}
+
+impl LoginUrl {
+    pub fn matches(&self, url: &str) -> bool {
+        match self.match_rule {
+            UrlMatch::Domain => match (host(&self.url), host(url)) {
+                (Some(saved), Some(host)) => host == saved || host.ends_with(&format!(".{}", saved)),
+                _ => false,
+            },
+            UrlMatch::Host => host(&self.url).is_some_and(|saved| host(url) == Some(saved)),
+            UrlMatch::StartsWith => url.strip_prefix(self.url.as_str()).is_some_and(|rest| {
+                rest.is_empty() || rest.starts_with(['/', '?', '#']) || self.url.ends_with(['/', '?', '#'])
+            }),
+            UrlMatch::Exact => url == self.url,
+            UrlMatch::Never => false,
+        }
+    }
+}
+
+/// The lowercase host of `url`, without user info or port. Bare hosts (`example.com/login`) are
+/// accepted too.
+fn host(url: &str) -> Option<String> {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let host = match authority.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|byte| byte.is_ascii_digit()) => host,
+        _ => authority,
+    };
+
+    Some(host.trim_end_matches('.').to_ascii_lowercase()).filter(|host| !host.is_empty())
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct PasswordChange {
+    /// The password that was replaced.
+    pub password: String,
+    /// When it was replaced.
+    pub changed: bson::DateTime,
+}
+
+/// A username and password, and where to use them.
+///
+/// `Debug` never shows the password, or the passwords it replaced.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct Login {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub urls: Vec<LoginUrl>,
+    /// Older passwords, oldest first.
+    #[serde(default)]
+    pub password_history: Vec<PasswordChange>,
+    #[serde(flatten)]
+    pub additional_fields: BTreeMap<String, bson::Bson>,
+}
+
+impl fmt::Debug for PasswordChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasswordChange").field("password", &"***").field("changed", &self.changed).finish()
+    }
+}
+
+impl fmt::Debug for Login {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Login")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .field("urls", &self.urls)
+            .field("password_history", &self.password_history)
+            .field("additional_fields", &self.additional_fields)
+            .finish()
+    }
+}
+
+impl Login {
+    pub fn new(username: &str, password: &str) -> Self {
+        Self {
+            username: username.to_string(),
+            password: password.to_string(),
+            urls: Vec::new(),
+            password_history: Vec::new(),
+            additional_fields: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_url(mut self, url: &str, match_rule: UrlMatch) -> Self {
+        self.urls.push(LoginUrl {
+            url: url.to_string(),
+            match_rule,
+        });
+        self
+    }
+
+    /// Replaces the password, moving the current one to `PASSWORD_HISTORY`.
+    pub fn set_password(&mut self, password: &str) {
+        if password == self.password {
+            return;
+        }
+
+        let old = std::mem::replace(&mut self.password, password.to_string());
+
+        if !old.is_empty() {
+            self.password_history.push(PasswordChange {
+                password: old,
+                changed: bson::DateTime::now(),
+            });
+        }
+    }
+
+    /// Whether this login should be offered for `url`.
+    pub fn matches(&self, url: &str) -> bool {
+        self.urls.iter().any(|saved| saved.matches(url))
+    }
+}
+
+impl Waiter for Login {
+    fn name() -> String {
+        "login".to_string()
+    }
+
+    fn version() -> String {
+        "0.0.0".to_string()
+    }
+
+    fn into_entry(self) -> Result<Entry> {
+        bson::to_document(&self)?.and_defaults::<Self>()
+    }
+
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
+
+        let login = bson::from_document(entry)?;
+
+        Ok(login)
+    }
+}
+
+impl Metadata for Login {
+    fn get_meta(&self, key: &str) -> Option<&bson::Bson> {
+        self.additional_fields.get(key)
+    }
+
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
+    }
+}
@@ -4,27 +4,134 @@
  * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
  */
 use crate::database::Entry;
+use crate::error::{get_str, Error, Result};
+use semver::{Version, VersionReq};
 
 pub mod sensitive_text;
 pub mod burrito_box;
 pub mod recursive;
 pub mod burrito_box_sym;
+pub mod registry;
+pub mod key_file;
+pub mod burrito_aead_box;
+pub mod burrito_multi_box;
+pub mod burrito_stream_box;
+pub mod sensitive_binary;
+pub mod totp;
+pub mod login;
+pub mod ssh_key;
+pub mod payment_card;
+pub mod detached_signature;
+pub mod trust_store;
+
+/// One step in the upgrade path of a waiter: documents whose version matches `from` are
+/// rewritten by `migrate` into the layout of version `to`.
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub from: VersionReq,
+    pub to: Version,
+    pub migrate: fn(Entry) -> Result<Entry>,
+}
+
+impl Migration {
+    /// Meant for the literal tables returned by [`Waiter::migrations`].
+    ///
+    /// # Panics
+    ///
+    /// If `from` or `to` is not valid semver.
+    pub fn new(from: &str, to: &str, migrate: fn(Entry) -> Result<Entry>) -> Self {
+        Self {
+            from: VersionReq::parse(from).expect("migration `from` must be a valid semver requirement"),
+            to: Version::parse(to).expect("migration `to` must be a valid semver version"),
+            migrate,
+        }
+    }
+}
+
+pub fn parse_version(version: &str) -> Result<Version> {
+    Version::parse(version).map_err(|err| Error::invalid_field("version", err))
+}
+
+/// Two versions are compatible if they fall into the same semver caret range, i.e. they only
+/// differ in changes that are promised to be backwards compatible.
+pub fn is_compatible(a: &Version, b: &Version) -> bool {
+    use semver::{Comparator, Op};
+
+    let (low, high) = if a <= b { (a, b) } else { (b, a) };
+
+    Comparator {
+        op: Op::Caret,
+        major: low.major,
+        minor: Some(low.minor),
+        patch: Some(low.patch),
+        pre: low.pre.clone(),
+    }
+        .matches(high)
+}
 
 pub trait Waiter: Sized {
     fn name() -> String;
     fn version() -> String;
-    fn into_entry(self) -> Entry;
-    fn from_entry(entry: Entry) -> anyhow::Result<Self>;
+    fn into_entry(self) -> Result<Entry>;
+    fn from_entry(entry: Entry) -> Result<Self>;
 
-    fn verify_version(cmp: &Entry) -> anyhow::Result<()> {
-        use anyhow::bail;
+    /// The upgrade path from older, incompatible versions of this waiter.
+    fn migrations() -> Vec<Migration> {
+        Vec::new()
+    }
 
-        let cmp = cmp.get_str("version")?;
+    fn verify_waiter(cmp: &Entry) -> Result<()> {
+        let cmp = get_str(cmp, "waiter")?;
 
-        if cmp != Self::version() {
-            bail!("Version mismatch: expected {}, got {}", Self::version(), cmp);
+        if cmp != Self::name() {
+            return Err(Error::WrongWaiter { expected: Self::name(), found: cmp.to_string() });
         }
 
         Ok(())
     }
+
+    fn verify_version(cmp: &Entry) -> Result<()> {
+        let found = parse_version(get_str(cmp, "version")?)?;
+        let expected = parse_version(&Self::version())?;
+
+        if !is_compatible(&found, &expected) {
+            return Err(Error::VersionMismatch { waiter: Self::name(), expected: Self::version(), found: found.to_string() });
+        }
+
+        Ok(())
+    }
+
+    /// Brings `entry` from version `from` up to a version compatible with [`Waiter::version`], by
+    /// applying [`Waiter::migrations`] one step at a time.
+    fn migrate(from: Version, mut entry: Entry) -> Result<Entry> {
+        let current = parse_version(&Self::version())?;
+        let migrations = Self::migrations();
+
+        let mut version = from;
+        while !is_compatible(&version, &current) {
+            let step = migrations
+                .iter()
+                // A step must move forward, otherwise a bad migration table would loop forever.
+                .find(|step| step.from.matches(&version) && step.to > version);
+
+            let Some(step) = step else {
+                return Err(Error::VersionMismatch { waiter: Self::name(), expected: Self::version(), found: version.to_string() });
+            };
+
+            entry = (step.migrate)(entry)?;
+            version = step.to.clone();
+            entry.insert("version", version.to_string());
+        }
+
+        Ok(entry)
+    }
+
+    /// Checks the waiter name, and migrates `entry` if it was written by an older version.
+    fn upgrade(entry: Entry) -> Result<Entry> {
+        Self::verify_waiter(&entry)?;
+
+        let from = parse_version(get_str(&entry, "version")?)?;
+
+        Self::migrate(from, entry)
+    }
 }
\ No newline at end of file
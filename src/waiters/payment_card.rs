@@ -0,0 +1,268 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::database::{Entry, Metadata};
+use crate::error::{Error, Result};
+use crate::waiters::Waiter;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum CardBrand {
+    Visa,
+    Mastercard,
+    AmericanExpress,
+    Discover,
+    DinersClub,
+    Jcb,
+    UnionPay,
+    Unknown,
+}
+
+impl CardBrand {
+    /// Guesses the brand from the first digits (the IIN) of a card number.
+    pub fn detect(number: &str) -> Self {
+        let prefix = |len: usize| number.get(..len).and_then(|digits| digits.parse::<u32>().ok()).unwrap_or(0);
+
+        match (prefix(1), prefix(2), prefix(3), prefix(4)) {
+            (4, ..) => CardBrand::Visa,
+            (_, 51..=55, ..) | (.., 2221..=2720) => CardBrand::Mastercard,
+            (_, 34 | 37, ..) => CardBrand::AmericanExpress,
+            (_, 65, ..) | (.., 6011) | (_, _, 644..=649, _) => CardBrand::Discover,
+            (_, 36 | 38 | 39, ..) | (_, _, 300..=305, _) => CardBrand::DinersClub,
+            (.., 3528..=3589) => CardBrand::Jcb,
+            (_, 62, ..) => CardBrand::UnionPay,
+            _ => CardBrand::Unknown,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CardBrand::Visa => "Visa",
+            CardBrand::Mastercard => "Mastercard",
+            CardBrand::AmericanExpress => "American Express",
+            CardBrand::Discover => "Discover",
+            CardBrand::DinersClub => "Diners Club",
+            CardBrand::Jcb => "JCB",
+            CardBrand::UnionPay => "UnionPay",
+            CardBrand::Unknown => "Card",
+        }
+    }
+
+    /// How the brand prints a number of `len` digits, e.g. 4-6-5 for a 15-digit American Express.
+    pub fn groups(self, len: usize) -> Vec<usize> {
+        match (self, len) {
+            (CardBrand::AmericanExpress, 15) => vec![4, 6, 5],
+            (CardBrand::DinersClub, 14) => vec![4, 6, 4],
+            _ => {
+                let mut groups = vec![4; len / 4];
+                if !len.is_multiple_of(4) {
+                    groups.push(len % 4);
+                }
+                groups
+            }
+        }
+    }
+}
+
+/// A credit or debit card.
+///
+/// Neither `Display` nor `Debug` print the full number or the CVV, so cards are safe to log.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct PaymentCard {
+    pub cardholder: String,
+    /// Digits only.
+    pub number: String,
+    /// 1 to 12.
+    pub expiry_month: u32,
+    /// With the century, e.g. `2027`.
+    pub expiry_year: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cvv: Option<String>,
+    pub brand: CardBrand,
+    #[serde(flatten)]
+    pub additional_fields: BTreeMap<String, bson::Bson>,
+}
+
+impl PaymentCard {
+    /// Spaces and dashes in `number` are ignored. Fails if the number doesn't pass the Luhn check,
+    /// or if the card has already expired.
+    pub fn new(cardholder: &str, number: &str, expiry_month: u32, expiry_year: u32, cvv: Option<&str>) -> Result<Self> {
+        let number: String = number.chars().filter(|digit| !matches!(digit, ' ' | '-')).collect();
+
+        let card = Self {
+            cardholder: cardholder.to_string(),
+            brand: CardBrand::detect(&number),
+            number,
+            expiry_month,
+            expiry_year,
+            cvv: cvv.map(str::to_string),
+            additional_fields: BTreeMap::new(),
+        };
+
+        card.validate()?;
+
+        let (year, month) = current_month();
+        if card.is_expired_at(year, month) {
+            return Err(Error::invalid_field("EXPIRY_YEAR", "the card has expired"));
+        }
+
+        Ok(card)
+    }
+
+    /// Checks the number, expiry month and CVV. Expired cards are still valid: they stay in the
+    /// vault after they expire.
+    pub fn validate(&self) -> Result<()> {
+        if !(12..=19).contains(&self.number.len()) || !self.number.bytes().all(|digit| digit.is_ascii_digit()) {
+            return Err(Error::invalid_field("NUMBER", "expected 12 to 19 digits"));
+        }
+
+        if !luhn(&self.number) {
+            return Err(Error::invalid_field("NUMBER", "fails the Luhn check"));
+        }
+
+        if !(1..=12).contains(&self.expiry_month) {
+            return Err(Error::invalid_field("EXPIRY_MONTH", "expected 1 to 12"));
+        }
+
+        if let Some(cvv) = &self.cvv {
+            if !(3..=4).contains(&cvv.len()) || !cvv.bytes().all(|digit| digit.is_ascii_digit()) {
+                return Err(Error::invalid_field("CVV", "expected 3 or 4 digits"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Cards are valid until the end of their expiry month.
+    pub fn is_expired_at(&self, year: u32, month: u32) -> bool {
+        (self.expiry_year, self.expiry_month) < (year, month)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let (year, month) = current_month();
+
+        self.is_expired_at(year, month)
+    }
+
+    pub fn last_four(&self) -> &str {
+        let start = self.number.char_indices().rev().nth(3).map_or(0, |(index, _)| index);
+
+        &self.number[start..]
+    }
+
+    /// Every digit but the last four replaced with `*`, grouped the way the card prints them:
+    /// `**** **** **** 4242`, or `**** ****** *0005` for American Express.
+    pub fn masked_number(&self) -> String {
+        let last_four = self.last_four();
+        let hidden = self.number.chars().count() - last_four.chars().count();
+        let mut digits = std::iter::repeat_n('*', hidden).chain(last_four.chars());
+
+        self.brand
+            .groups(hidden + last_four.chars().count())
+            .into_iter()
+            .map(|len| digits.by_ref().take(len).collect::<String>())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl fmt::Display for PaymentCard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} ({:02}/{:02})", self.brand.as_str(), self.masked_number(), self.expiry_month, self.expiry_year % 100)
+    }
+}
+
+impl fmt::Debug for PaymentCard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PaymentCard")
+            .field("cardholder", &self.cardholder)
+            .field("number", &self.masked_number())
+            .field("expiry_month", &self.expiry_month)
+            .field("expiry_year", &self.expiry_year)
+            .field("cvv", &self.cvv.as_ref().map(|_| "***"))
+            .field("brand", &self.brand)
+            .field("additional_fields", &self.additional_fields)
+            .finish()
+    }
+}
+
+pub fn luhn(number: &str) -> bool {
+    let mut sum = 0;
+
+    for (i, digit) in number.bytes().rev().enumerate() {
+        if !digit.is_ascii_digit() {
+            return false;
+        }
+
+        let mut digit = (digit - b'0') as u32;
+        if i % 2 == 1 {
+            digit *= 2;
+            if digit > 9 {
+                digit -= 9;
+            }
+        }
+
+        sum += digit;
+    }
+
+    !number.is_empty() && sum % 10 == 0
+}
+
+/// The current (year, month) in UTC.
+fn current_month() -> (u32, u32) {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let days = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs() / 86_400).unwrap_or(0) as i64;
+
+    // Days since the epoch to a civil date (Howard Hinnant's `civil_from_days`).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year as u32, month as u32)
+}
+
+impl Waiter for PaymentCard {
+    fn name() -> String {
+        "payment_card".to_string()
+    }
+
+    fn version() -> String {
+        "0.0.0".to_string()
+    }
+
+    fn into_entry(self) -> Result<Entry> {
+        bson::to_document(&self)?.and_defaults::<Self>()
+    }
+
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
+
+        let card: Self = bson::from_document(entry)?;
+        card.validate()?;
+
+        Ok(card)
+    }
+}
+
+impl Metadata for PaymentCard {
+    fn get_meta(&self, key: &str) -> Option<&bson::Bson> {
+        self.additional_fields.get(key)
+    }
+
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
+    }
+}
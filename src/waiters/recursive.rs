@@ -4,10 +4,14 @@
  * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
  */
 use crate::database::{Entry, Metadata};
+use crate::error::{Error, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use crate::waiters::Waiter;
 
+/// `burrito_recursive` entries act as folders ("groups"). Paths are `/`-separated, and each
+/// segment is the `title` or the `uuid` of a child. When several children match, the first one wins.
+/// Titles containing a `/` can only be addressed by uuid.
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub struct Recursive {
@@ -20,6 +24,281 @@ impl Recursive {
     pub fn new(children: Vec<Entry>) -> Self {
         Self { children, additional_fields: BTreeMap::new() }
     }
+
+    /// An empty group, with a `title` and a fresh `uuid`. Any other metadata (`icon`, `notes`,
+    /// `tags`...) can be set on it like on any other entry.
+    pub fn group(title: &str) -> Result<Self> {
+        Self::new(Vec::new())
+            .with_meta(("title", title))?
+            .with_meta(("uuid", bson::Uuid::new()))
+    }
+
+    pub fn is_group(entry: &Entry) -> bool {
+        entry.get_str("waiter") == Ok("burrito_recursive")
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Entry> {
+        let mut segments = segments(path);
+        let mut entry = find(self.children.iter(), segments.next()?)?;
+
+        for segment in segments {
+            entry = find(children(entry), segment)?;
+        }
+
+        Some(entry)
+    }
+
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut Entry> {
+        let mut segments = segments(path);
+        let first = segments.next()?;
+        let mut entry = self.children.iter_mut().find(|child| matches(child, first))?;
+
+        for segment in segments {
+            if !Self::is_group(entry) {
+                return None;
+            }
+
+            entry = entry
+                .get_array_mut("CHILDREN")
+                .ok()?
+                .iter_mut()
+                .filter_map(bson::Bson::as_document_mut)
+                .find(|child| matches(child, segment))?;
+        }
+
+        Some(entry)
+    }
+
+    /// Appends `entry` to the group at `group_path`. An empty path is this entry itself.
+    pub fn insert_at(&mut self, group_path: &str, entry: Entry) -> Result<()> {
+        self.group_mut(group_path)?.insert(None, entry);
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, path: &str) -> Result<Entry> {
+        let (parent, name) = split_last(path)?;
+
+        let mut group = self.group_mut(parent)?;
+        let index = group.position(name).ok_or_else(|| Error::PathNotFound(path.to_string()))?;
+
+        Ok(group.remove(index))
+    }
+
+    /// Moves the entry at `path` to the end of the group at `group_path`. A group cannot be moved
+    /// into itself or into one of its descendants.
+    pub fn move_to(&mut self, path: &str, group_path: &str) -> Result<()> {
+        let moved = self.get(path).ok_or_else(|| Error::PathNotFound(path.to_string()))?;
+
+        let target = if segments(group_path).next().is_none() {
+            None
+        } else {
+            let target = self.get(group_path).ok_or_else(|| Error::PathNotFound(group_path.to_string()))?;
+            if !Self::is_group(target) {
+                return Err(Error::NotAGroup(group_path.to_string()));
+            }
+
+            Some(target)
+        };
+
+        if let Some(target) = target {
+            let mut subtree = Nodes::depth_first(vec![moved]);
+            if subtree.any(|node| std::ptr::eq(node.entry, target)) {
+                return Err(Error::InvalidPath(format!("cannot move {} into itself", path)));
+            }
+        }
+
+        let (parent, name) = split_last(path)?;
+        let (index, entry) = {
+            let mut group = self.group_mut(parent)?;
+            let index = group.position(name).ok_or_else(|| Error::PathNotFound(path.to_string()))?;
+
+            (index, group.remove(index))
+        };
+
+        // Removing the entry may change what `group_path` resolves to when titles are ambiguous, so
+        // put it back where it was if the target is gone.
+        match self.group_mut(group_path) {
+            Ok(mut group) => group.insert(None, entry),
+            Err(err) => {
+                self.group_mut(parent)?.insert(Some(index), entry);
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every descendant, parents before their children, in order.
+    pub fn depth_first(&self) -> Nodes<'_> {
+        Nodes::depth_first(self.children.iter().collect())
+    }
+
+    /// Every descendant, level by level.
+    pub fn breadth_first(&self) -> Nodes<'_> {
+        Nodes::breadth_first(self.children.iter().collect())
+    }
+
+    fn group_mut(&mut self, group_path: &str) -> Result<Children<'_>> {
+        if segments(group_path).next().is_none() {
+            return Ok(Children::Root(&mut self.children));
+        }
+
+        let group = self.get_mut(group_path).ok_or_else(|| Error::PathNotFound(group_path.to_string()))?;
+        if !Self::is_group(group) {
+            return Err(Error::NotAGroup(group_path.to_string()));
+        }
+
+        if !group.contains_key("CHILDREN") {
+            group.insert("CHILDREN", bson::Bson::Array(Vec::new()));
+        }
+
+        let children = group
+            .get_array_mut("CHILDREN")
+            .map_err(|_| Error::invalid_field("CHILDREN", "expected an array"))?;
+
+        Ok(Children::Nested(children))
+    }
+}
+
+/// The children of the top-level group are `Entry`s, the children of nested groups are BSON values.
+enum Children<'a> {
+    Root(&'a mut Vec<Entry>),
+    Nested(&'a mut Vec<bson::Bson>),
+}
+
+impl Children<'_> {
+    fn position(&self, segment: &str) -> Option<usize> {
+        match self {
+            Children::Root(children) => children.iter().position(|child| matches(child, segment)),
+            Children::Nested(children) => children.iter().position(|child| child.as_document().is_some_and(|child| matches(child, segment))),
+        }
+    }
+
+    fn remove(&mut self, index: usize) -> Entry {
+        match self {
+            Children::Root(children) => children.remove(index),
+            Children::Nested(children) => match children.remove(index) {
+                bson::Bson::Document(entry) => entry,
+                _ => unreachable!("`position` only finds documents"),
+            },
+        }
+    }
+
+    /// Appends `entry`, or inserts it at `index`.
+    fn insert(&mut self, index: Option<usize>, entry: Entry) {
+        match self {
+            Children::Root(children) => children.insert(index.unwrap_or(children.len()), entry),
+            Children::Nested(children) => children.insert(index.unwrap_or(children.len()), bson::Bson::Document(entry)),
+        }
+    }
+}
+
+/// A node visited by [`Recursive::depth_first`] or [`Recursive::breadth_first`].
+pub struct Node<'a> {
+    /// The path of the node, if it and all its parents can be addressed (have a title or uuid).
+    pub path: Option<String>,
+    /// 0 for the children of the top-level group.
+    pub depth: usize,
+    pub entry: &'a Entry,
+}
+
+pub struct Nodes<'a> {
+    queue: VecDeque<Node<'a>>,
+    depth_first: bool,
+}
+
+impl<'a> Nodes<'a> {
+    fn depth_first(roots: Vec<&'a Entry>) -> Self {
+        Self::from_roots(roots, true)
+    }
+
+    fn breadth_first(roots: Vec<&'a Entry>) -> Self {
+        Self::from_roots(roots, false)
+    }
+
+    fn from_roots(roots: Vec<&'a Entry>, depth_first: bool) -> Self {
+        let queue = roots
+            .into_iter()
+            .map(|entry| Node { path: segment_of(entry), depth: 0, entry })
+            .collect();
+
+        Self { queue, depth_first }
+    }
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.queue.pop_front()?;
+
+        let children = children(node.entry).map(|child| Node {
+            path: node.path.as_ref().zip(segment_of(child)).map(|(parent, segment)| format!("{}/{}", parent, segment)),
+            depth: node.depth + 1,
+            entry: child,
+        });
+
+        if self.depth_first {
+            let children: Vec<_> = children.collect();
+            for child in children.into_iter().rev() {
+                self.queue.push_front(child);
+            }
+        } else {
+            self.queue.extend(children);
+        }
+
+        Some(node)
+    }
+}
+
+fn segments(path: &str) -> impl Iterator<Item=&str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+fn split_last(path: &str) -> Result<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+
+    if name.is_empty() {
+        return Err(Error::InvalidPath("the path is empty".to_string()));
+    }
+
+    Ok((parent, name))
+}
+
+/// The children of a group, or nothing for any other entry.
+fn children(entry: &Entry) -> impl Iterator<Item=&Entry> {
+    Some(entry)
+        .filter(|entry| Recursive::is_group(entry))
+        .and_then(|entry| entry.get_array("CHILDREN").ok())
+        .into_iter()
+        .flatten()
+        .filter_map(bson::Bson::as_document)
+}
+
+fn find<'a>(mut children: impl Iterator<Item=&'a Entry>, segment: &str) -> Option<&'a Entry> {
+    children.find(|child| matches(child, segment))
+}
+
+fn matches(entry: &Entry, segment: &str) -> bool {
+    entry.get_str("title") == Ok(segment) || uuid_of(entry).is_some_and(|uuid| uuid == segment)
+}
+
+fn uuid_of(entry: &Entry) -> Option<String> {
+    match entry.get("uuid")? {
+        bson::Bson::Binary(binary) => binary.to_uuid().ok().map(|uuid| uuid.to_string()),
+        bson::Bson::String(uuid) => Some(uuid.clone()),
+        _ => None,
+    }
+}
+
+/// How a path names `entry`: its title, or its uuid if the title can't be used.
+pub(crate) fn segment_of(entry: &Entry) -> Option<String> {
+    match entry.get_str("title") {
+        Ok(title) if !title.is_empty() && !title.contains('/') => Some(title.to_string()),
+        _ => uuid_of(entry),
+    }
 }
 
 impl Waiter for Recursive {
@@ -31,14 +310,14 @@ impl Waiter for Recursive {
         "0.0.0".to_string()
     }
 
-    fn into_entry(self) -> Entry {
-        let entry = bson::to_document(&self).unwrap();
+    fn into_entry(self) -> Result<Entry> {
+        let entry = bson::to_document(&self)?;
 
         entry.and_defaults::<Self>()
     }
 
-    fn from_entry(entry: Entry) -> anyhow::Result<Self> {
-        Self::verify_version(&entry)?;
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
 
         let entry = bson::from_document(entry)?;
 
@@ -47,8 +326,9 @@ impl Waiter for Recursive {
 }
 
 impl Metadata for Recursive {
-    fn set_meta(&mut self, metadata: (&str, impl Serialize)) {
-        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1).unwrap());
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
     }
 
     fn get_meta(&self, key: &str) -> Option<&bson::Bson> {
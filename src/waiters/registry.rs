@@ -0,0 +1,189 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::database::Entry;
+use crate::error::{get_str, Error, Result};
+use crate::waiters::burrito_aead_box::BurritoAeadBox;
+use crate::waiters::burrito_box::BurritoBox;
+use crate::waiters::burrito_box_sym::BurritoBoxSym;
+use crate::waiters::burrito_multi_box::BurritoMultiBox;
+use crate::waiters::burrito_stream_box::BurritoStreamBox;
+use crate::waiters::sensitive_binary::SensitiveBinary;
+use crate::waiters::totp::Totp;
+use crate::waiters::login::Login;
+use crate::waiters::ssh_key::SshKey;
+use crate::waiters::payment_card::PaymentCard;
+use crate::waiters::detached_signature::DetachedSignature;
+use crate::waiters::trust_store::TrustStore;
+use crate::waiters::key_file::KeyFile;
+use crate::waiters::recursive::Recursive;
+use crate::waiters::sensitive_text::SensitiveText;
+use crate::waiters::Waiter;
+use std::any::Any;
+use std::collections::HashMap;
+
+/// Object-safe view of a [`Waiter`], for when the concrete type is only known at runtime.
+pub trait AnyWaiter: Any {
+    fn waiter_name(&self) -> String;
+    fn waiter_version(&self) -> String;
+    fn into_entry_boxed(self: Box<Self>) -> Result<Entry>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Waiter + 'static> AnyWaiter for T {
+    fn waiter_name(&self) -> String {
+        T::name()
+    }
+
+    fn waiter_version(&self) -> String {
+        T::version()
+    }
+
+    fn into_entry_boxed(self: Box<Self>) -> Result<Entry> {
+        (*self).into_entry()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl dyn AnyWaiter {
+    pub fn is<T: Waiter + 'static>(&self) -> bool {
+        self.as_any().is::<T>()
+    }
+
+    pub fn downcast_ref<T: Waiter + 'static>(&self) -> Option<&T> {
+        self.as_any().downcast_ref()
+    }
+
+    pub fn downcast_mut<T: Waiter + 'static>(&mut self) -> Option<&mut T> {
+        self.as_any_mut().downcast_mut()
+    }
+}
+
+pub type Decoder = Box<dyn Fn(Entry) -> Result<Box<dyn AnyWaiter>> + Send + Sync>;
+
+/// Maps the `waiter` field of an entry to the code that understands it.
+pub struct WaiterRegistry {
+    decoders: HashMap<String, Decoder>,
+}
+
+impl WaiterRegistry {
+    /// A registry that knows no waiters at all.
+    pub fn empty() -> Self {
+        Self { decoders: HashMap::new() }
+    }
+
+    /// A registry that knows every waiter shipped with this crate.
+    pub fn new() -> Self {
+        Self::empty()
+            .with::<SensitiveText>()
+            .with::<Recursive>()
+            .with::<BurritoBox>()
+            .with::<BurritoBoxSym>()
+            .with::<KeyFile>()
+            .with::<BurritoAeadBox>()
+            .with::<BurritoMultiBox>()
+            .with::<BurritoStreamBox>()
+            .with::<SensitiveBinary>()
+            .with::<Totp>()
+            .with::<Login>()
+            .with::<SshKey>()
+            .with::<PaymentCard>()
+            .with::<DetachedSignature>()
+            .with::<TrustStore>()
+    }
+
+    pub fn register<T: Waiter + 'static>(&mut self) {
+        self.register_with(T::name(), |entry| Ok(Box::new(T::from_entry(entry)?)));
+    }
+
+    /// Registers a custom decoder under `name`, replacing any waiter previously registered with that name.
+    pub fn register_with(
+        &mut self,
+        name: impl Into<String>,
+        decoder: impl Fn(Entry) -> Result<Box<dyn AnyWaiter>> + Send + Sync + 'static,
+    ) {
+        self.decoders.insert(name.into(), Box::new(decoder));
+    }
+
+    pub fn with<T: Waiter + 'static>(mut self) -> Self {
+        self.register::<T>();
+        self
+    }
+
+    pub fn unregister(&mut self, name: &str) -> bool {
+        self.decoders.remove(name).is_some()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.decoders.contains_key(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item=&str> {
+        self.decoders.keys().map(String::as_str)
+    }
+
+    pub fn decode(&self, entry: Entry) -> Result<Box<dyn AnyWaiter>> {
+        let name = get_str(&entry, "waiter")?;
+
+        let Some(decoder) = self.decoders.get(name) else { return Err(Error::UnknownWaiter(name.to_string())) };
+
+        decoder(entry)
+    }
+
+    /// Decodes and re-encodes `entry` and every child of every `burrito_recursive` below it, so that
+    /// entries written by older versions of a waiter are migrated and saved in the current layout.
+    ///
+    /// Entries with a waiter this registry does not know are kept as they are.
+    pub fn upgrade(&self, entry: Entry) -> Result<Entry> {
+        if !self.contains(get_str(&entry, "waiter")?) {
+            return Ok(entry);
+        }
+
+        let mut waiter = self.decode(entry)?;
+
+        if let Some(recursive) = waiter.downcast_mut::<Recursive>() {
+            let children = std::mem::take(&mut recursive.children);
+            recursive.children = children
+                .into_iter()
+                .map(|child| self.upgrade(child))
+                .collect::<Result<_>>()?;
+        }
+
+        waiter.into_entry_boxed()
+    }
+
+    /// Decodes `entry` and every child of every `burrito_recursive` below it, depth-first.
+    ///
+    /// Entries that cannot be decoded are handed to `visit` as errors instead of stopping the walk, so
+    /// that one unknown or broken node does not hide the rest of the tree.
+    pub fn walk(&self, entry: Entry, visit: &mut impl FnMut(Result<&dyn AnyWaiter>)) {
+        let waiter = match self.decode(entry) {
+            Ok(waiter) => waiter,
+            Err(err) => return visit(Err(err)),
+        };
+
+        visit(Ok(waiter.as_ref()));
+
+        if let Some(recursive) = waiter.downcast_ref::<Recursive>() {
+            for child in &recursive.children {
+                self.walk(child.clone(), visit);
+            }
+        }
+    }
+}
+
+impl Default for WaiterRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
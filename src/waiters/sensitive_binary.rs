@@ -0,0 +1,151 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::database::{Entry, Metadata};
+use crate::encryption::blake2b;
+use crate::error::{Error, Result};
+use crate::waiters::Waiter;
+use bson::spec::BinarySubtype;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+/// Raw bytes: SSH keys, certificates, recovery PDFs... Like `sensitive_text`, this is not encrypted
+/// by itself, so keep it inside a box.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct SensitiveBinary {
+    pub data: bson::Binary,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    pub mime_type: String,
+    pub size: i64,
+    /// BLAKE2b-256 of `DATA`.
+    pub hash: bson::Binary,
+    #[serde(flatten)]
+    pub additional_fields: BTreeMap<String, bson::Bson>,
+}
+
+impl SensitiveBinary {
+    /// Without a `mime_type`, it is guessed from the extension of `filename`.
+    pub fn new(data: Vec<u8>, filename: Option<&str>, mime_type: Option<&str>) -> Result<Self> {
+        let mime_type = match mime_type {
+            Some(mime_type) => mime_type,
+            None => filename.map(mime_type_for).unwrap_or(DEFAULT_MIME_TYPE),
+        };
+
+        Ok(Self {
+            size: data.len() as i64,
+            hash: hash(&data)?,
+            data: bson::Binary {
+                subtype: BinarySubtype::Sensitive,
+                bytes: data,
+            },
+            filename: filename.map(str::to_string),
+            mime_type: mime_type.to_string(),
+            additional_fields: BTreeMap::new(),
+        })
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)?;
+        let filename = path.file_name().map(|name| name.to_string_lossy());
+
+        Self::new(data, filename.as_deref(), None)
+    }
+
+    /// Writes `DATA` to `path`, after checking it against `SIZE` and `HASH`.
+    pub fn to_path(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.verify()?;
+
+        crate::vault::write_atomic(path.as_ref(), self.data())
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data.bytes
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data.bytes
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        if self.size != self.data.bytes.len() as i64 {
+            return Err(Error::invalid_field("SIZE", format!("DATA is {} bytes long", self.data.bytes.len())));
+        }
+
+        if hash(&self.data.bytes)?.bytes != self.hash.bytes {
+            return Err(Error::invalid_field("HASH", "does not match DATA"));
+        }
+
+        Ok(())
+    }
+}
+
+fn hash(data: &[u8]) -> Result<bson::Binary> {
+    Ok(bson::Binary {
+        subtype: BinarySubtype::Generic,
+        bytes: blake2b(data, None, 32)?,
+    })
+}
+
+/// Guesses a MIME type from a file name. Only knows the kinds of files people keep in a vault.
+pub fn mime_type_for(filename: &str) -> &'static str {
+    let extension = match filename.rsplit_once('.') {
+        Some((_, extension)) => extension.to_ascii_lowercase(),
+        None => return DEFAULT_MIME_TYPE,
+    };
+
+    match extension.as_str() {
+        "txt" => "text/plain",
+        "pdf" => "application/pdf",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "json" => "application/json",
+        "pem" | "crt" | "cer" => "application/x-pem-file",
+        "der" => "application/x-x509-ca-cert",
+        "p12" | "pfx" => "application/x-pkcs12",
+        "jks" => "application/x-java-keystore",
+        "asc" | "gpg" | "pgp" => "application/pgp-keys",
+        "zip" => "application/zip",
+        _ => DEFAULT_MIME_TYPE,
+    }
+}
+
+impl Waiter for SensitiveBinary {
+    fn name() -> String {
+        "sensitive_binary".to_string()
+    }
+
+    fn version() -> String {
+        "0.0.0".to_string()
+    }
+
+    fn into_entry(self) -> Result<Entry> {
+        bson::to_document(&self)?.and_defaults::<Self>()
+    }
+
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
+
+        let binary = bson::from_document(entry)?;
+
+        Ok(binary)
+    }
+}
+
+impl Metadata for SensitiveBinary {
+    fn get_meta(&self, key: &str) -> Option<&bson::Bson> {
+        self.additional_fields.get(key)
+    }
+
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
+    }
+}
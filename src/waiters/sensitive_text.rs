@@ -4,6 +4,7 @@
  * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
  */
 use crate::database::{Entry, Metadata};
+use crate::error::Result;
 use bson::doc;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
@@ -34,14 +35,14 @@ impl Waiter for SensitiveText {
     fn version() -> String {
         "0.0.0".to_string()
     }
-    fn into_entry(self) -> Entry {
-        let entry = bson::ser::to_document(&self).unwrap();
+    fn into_entry(self) -> Result<Entry> {
+        let entry = bson::ser::to_document(&self)?;
 
         entry.and_defaults::<Self>()
     }
 
-    fn from_entry(entry: Entry) -> anyhow::Result<Self> {
-        Self::verify_version(&entry)?;
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
 
         let entry: SensitiveText = bson::de::from_document(entry)?;
 
@@ -54,7 +55,8 @@ impl Metadata for SensitiveText {
         self.additional_fields.get(key)
     }
 
-    fn set_meta(&mut self, metadata: (&str, impl Serialize)) {
-        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1).unwrap());
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,419 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::database::{Entry, Metadata};
+use crate::error::{Error, Result};
+use crate::waiters::Waiter;
+use bson::spec::BinarySubtype;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OtpAlgorithm {
+    #[default]
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl OtpAlgorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OtpAlgorithm::Sha1 => "SHA1",
+            OtpAlgorithm::Sha256 => "SHA256",
+            OtpAlgorithm::Sha512 => "SHA512",
+        }
+    }
+
+    fn hmac(self, key: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+        use hmac::Hmac;
+
+        match self {
+            OtpAlgorithm::Sha1 => hmac::<Hmac<sha1::Sha1>>(key, message),
+            OtpAlgorithm::Sha256 => hmac::<Hmac<sha2::Sha256>>(key, message),
+            OtpAlgorithm::Sha512 => hmac::<Hmac<sha2::Sha512>>(key, message),
+        }
+    }
+}
+
+fn hmac<M: hmac::Mac + hmac::digest::KeyInit>(key: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+    let mut mac = <M as hmac::Mac>::new_from_slice(key).map_err(|_| Error::invalid_field("SECRET", "not a valid HMAC key"))?;
+    mac.update(message);
+
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OtpKind {
+    /// Time-based (RFC 6238): the moving factor is the number of `PERIOD`s since the Unix epoch.
+    Totp,
+    /// Counter-based (RFC 4226): the moving factor is `COUNTER`, bumped after every code.
+    Hotp,
+}
+
+/// A 2FA seed, as found in the QR code of an `otpauth://` URI.
+///
+/// `Debug` never shows the secret.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct Totp {
+    pub secret: bson::Binary,
+    pub kind: OtpKind,
+    pub algorithm: OtpAlgorithm,
+    pub digits: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub period: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counter: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    #[serde(flatten)]
+    pub additional_fields: BTreeMap<String, bson::Bson>,
+}
+
+impl fmt::Debug for Totp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Totp")
+            .field("secret", &"***")
+            .field("kind", &self.kind)
+            .field("algorithm", &self.algorithm)
+            .field("digits", &self.digits)
+            .field("period", &self.period)
+            .field("counter", &self.counter)
+            .field("issuer", &self.issuer)
+            .field("account", &self.account)
+            .field("additional_fields", &self.additional_fields)
+            .finish()
+    }
+}
+
+impl Totp {
+    pub const DEFAULT_DIGITS: u32 = 6;
+    pub const DEFAULT_PERIOD: u64 = 30;
+    /// The largest `COUNTER` or `PERIOD` an entry can hold: BSON integers are signed.
+    pub const MAX_COUNTER: u64 = i64::MAX as u64;
+
+    /// A TOTP seed with the defaults every authenticator app understands: SHA1, 6 digits, 30 seconds.
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self {
+            secret: bson::Binary {
+                subtype: BinarySubtype::Sensitive,
+                bytes: secret,
+            },
+            kind: OtpKind::Totp,
+            algorithm: OtpAlgorithm::default(),
+            digits: Self::DEFAULT_DIGITS,
+            period: Some(Self::DEFAULT_PERIOD),
+            counter: None,
+            issuer: None,
+            account: None,
+            additional_fields: BTreeMap::new(),
+        }
+    }
+
+    pub fn new_hotp(secret: Vec<u8>, counter: u64) -> Self {
+        Self {
+            kind: OtpKind::Hotp,
+            period: None,
+            counter: Some(counter),
+            ..Self::new(secret)
+        }
+    }
+
+    pub fn with_algorithm(mut self, algorithm: OtpAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn with_digits(mut self, digits: u32) -> Self {
+        self.digits = digits;
+        self
+    }
+
+    pub fn with_issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    pub fn with_account(mut self, account: &str) -> Self {
+        self.account = Some(account.to_string());
+        self
+    }
+
+    /// The RFC 4226 code for `counter`, zero-padded to `DIGITS`.
+    pub fn hotp(&self, counter: u64) -> Result<String> {
+        if !(6..=8).contains(&self.digits) {
+            return Err(Error::invalid_field("DIGITS", "must be between 6 and 8"));
+        }
+
+        let hash = self.algorithm.hmac(&self.secret.bytes, &counter.to_be_bytes())?;
+
+        // Dynamic truncation
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+
+        let code = binary % 10u32.pow(self.digits);
+
+        Ok(format!("{:0width$}", code, width = self.digits as usize))
+    }
+
+    /// The code at `unix_time` (in seconds). For HOTP, the time is ignored and `COUNTER` is used.
+    pub fn code_at(&self, unix_time: u64) -> Result<String> {
+        match self.kind {
+            OtpKind::Totp => {
+                let period = self.period.unwrap_or(Self::DEFAULT_PERIOD);
+                if period == 0 {
+                    return Err(Error::invalid_field("PERIOD", "must not be zero"));
+                }
+
+                self.hotp(unix_time / period)
+            }
+            OtpKind::Hotp => self.hotp(self.counter.unwrap_or(0)),
+        }
+    }
+
+    pub fn current_code(&self) -> Result<String> {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+
+        self.code_at(now)
+    }
+
+    /// Returns the HOTP code for the current `COUNTER`, and moves on to the next one. Save the
+    /// entry afterwards, or the same code will be generated again.
+    pub fn next_hotp(&mut self) -> Result<String> {
+        let counter = self.counter.unwrap_or(0);
+        // A code whose counter can't move on, or be saved, would be handed out forever.
+        let next = counter
+            .checked_add(1)
+            .filter(|next| *next <= Self::MAX_COUNTER)
+            .ok_or_else(|| Error::invalid_field("COUNTER", "cannot go any higher"))?;
+        let code = self.hotp(counter)?;
+
+        self.counter = Some(next);
+
+        Ok(code)
+    }
+
+    /// Parses a [Key URI](https://github.com/google/google-authenticator/wiki/Key-Uri-Format),
+    /// e.g. `otpauth://totp/Example:alice@example.com?secret=JBSWY3DPEHPK3PXP&issuer=Example`.
+    pub fn from_uri(uri: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidUri(reason.to_string());
+        let counter = |value: &str| value.parse::<u64>().ok().filter(|value| *value <= Self::MAX_COUNTER);
+
+        let Some(rest) = uri.strip_prefix("otpauth://") else {
+            return Err(invalid("expected the otpauth:// scheme"));
+        };
+
+        let (kind, rest) = rest.split_once('/').ok_or_else(|| invalid("missing label"))?;
+        let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let kind = match kind.to_ascii_lowercase().as_str() {
+            "totp" => OtpKind::Totp,
+            "hotp" => OtpKind::Hotp,
+            _ => return Err(invalid("expected totp or hotp")),
+        };
+
+        let label = percent_decode(label)?;
+        let (label_issuer, account) = match label.split_once(':') {
+            Some((issuer, account)) => (Some(issuer.to_string()), account.trim_start()),
+            None => (None, label.as_str()),
+        };
+
+        let mut otp = Self::new(Vec::new());
+        otp.kind = kind;
+        otp.issuer = label_issuer;
+        otp.account = Some(account.to_string()).filter(|account| !account.is_empty());
+
+        let mut secret = None;
+        for parameter in query.split('&').filter(|parameter| !parameter.is_empty()) {
+            let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            let value = percent_decode(value)?;
+
+            match key {
+                "secret" => secret = Some(base32_decode(&value).ok_or_else(|| invalid("secret is not valid base32"))?),
+                // The parameter wins over the label prefix, like in most authenticator apps.
+                "issuer" => otp.issuer = Some(value),
+                "algorithm" => {
+                    otp.algorithm = match value.to_ascii_uppercase().as_str() {
+                        "SHA1" => OtpAlgorithm::Sha1,
+                        "SHA256" => OtpAlgorithm::Sha256,
+                        "SHA512" => OtpAlgorithm::Sha512,
+                        _ => return Err(invalid("unknown algorithm")),
+                    }
+                }
+                "digits" => {
+                    otp.digits = value.parse().map_err(|_| invalid("digits is not a number"))?;
+                    if !(6..=8).contains(&otp.digits) {
+                        return Err(invalid("digits must be between 6 and 8"));
+                    }
+                }
+                "period" => otp.period = Some(counter(&value).ok_or_else(|| invalid("period is not a number"))?),
+                "counter" => otp.counter = Some(counter(&value).ok_or_else(|| invalid("counter is not a number"))?),
+                _ => {}
+            }
+        }
+
+        otp.secret.bytes = secret.filter(|secret| !secret.is_empty()).ok_or_else(|| invalid("missing secret"))?;
+
+        match otp.kind {
+            OtpKind::Totp => otp.counter = None,
+            OtpKind::Hotp => {
+                otp.period = None;
+                if otp.counter.is_none() {
+                    return Err(invalid("hotp requires a counter"));
+                }
+            }
+        }
+
+        Ok(otp)
+    }
+
+    pub fn to_uri(&self) -> String {
+        let kind = match self.kind {
+            OtpKind::Totp => "totp",
+            OtpKind::Hotp => "hotp",
+        };
+
+        let account = self.account.as_deref().unwrap_or_default();
+        let label = match &self.issuer {
+            Some(issuer) => format!("{}:{}", percent_encode(issuer), percent_encode(account)),
+            None => percent_encode(account),
+        };
+
+        let mut uri = format!(
+            "otpauth://{}/{}?secret={}&algorithm={}&digits={}",
+            kind,
+            label,
+            base32_encode(&self.secret.bytes),
+            self.algorithm.as_str(),
+            self.digits
+        );
+
+        if let Some(issuer) = &self.issuer {
+            uri += &format!("&issuer={}", percent_encode(issuer));
+        }
+
+        match self.kind {
+            OtpKind::Totp => uri += &format!("&period={}", self.period.unwrap_or(Self::DEFAULT_PERIOD)),
+            OtpKind::Hotp => uri += &format!("&counter={}", self.counter.unwrap_or(0)),
+        }
+
+        uri
+    }
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32, without padding (which is how `otpauth://` URIs carry secrets).
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer.iter().fold(0u64, |bits, byte| bits << 8 | *byte as u64);
+
+        for i in 0..(chunk.len() * 8).div_ceil(5) {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            out.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+
+    out
+}
+
+/// Lenient on input: case, spaces and padding are ignored.
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+
+    for character in text.bytes().filter(|character| !matches!(character, b' ' | b'-' | b'=')) {
+        let value = BASE32_ALPHABET.iter().position(|letter| *letter == character.to_ascii_uppercase())?;
+
+        bits = bits << 5 | value as u32;
+        bit_count += 5;
+
+        if bit_count >= 8 {
+            bit_count -= 8;
+            out.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+
+    Some(out)
+}
+
+fn percent_encode(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' => out.push(byte as char),
+            _ => out += &format!("%{:02X}", byte),
+        }
+    }
+
+    out
+}
+
+fn percent_decode(text: &str) -> Result<String> {
+    let invalid = || Error::InvalidUri("bad percent-encoding".to_string());
+
+    let mut out = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let high = bytes.next().and_then(|digit| (digit as char).to_digit(16)).ok_or_else(invalid)?;
+            let low = bytes.next().and_then(|digit| (digit as char).to_digit(16)).ok_or_else(invalid)?;
+            out.push((high * 16 + low) as u8);
+        } else {
+            out.push(byte);
+        }
+    }
+
+    String::from_utf8(out).map_err(|_| invalid())
+}
+
+impl Waiter for Totp {
+    fn name() -> String {
+        "totp".to_string()
+    }
+
+    fn version() -> String {
+        "0.0.0".to_string()
+    }
+
+    fn into_entry(self) -> Result<Entry> {
+        bson::to_document(&self)?.and_defaults::<Self>()
+    }
+
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
+
+        let totp = bson::from_document(entry)?;
+
+        Ok(totp)
+    }
+}
+
+impl Metadata for Totp {
+    fn get_meta(&self, key: &str) -> Option<&bson::Bson> {
+        self.additional_fields.get(key)
+    }
+
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::database::{Entry, Metadata};
+use crate::error::{key_from_slice, Error, Result};
+use crate::history::{History, Revision};
+use crate::signing::Signing;
+use crate::waiters::detached_signature::DetachedSignature;
+use crate::waiters::Waiter;
+use bson::spec::BinarySubtype;
+use bson::Bson;
+use dryoc::sign::protected::PublicKey;
+use dryoc::types::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A signing key someone approved, and when it may be used.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct TrustedKey {
+    pub public_key: bson::Binary,
+    pub label: String,
+    pub approved: bson::DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<bson::DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_after: Option<bson::DateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct Revocation {
+    pub public_key: bson::Binary,
+    pub revoked: bson::DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// The signing keys a user recognized and approved by hand, as the security guidelines ask.
+///
+/// Signatures are only accepted from keys of the store that are within their validity window and
+/// were never revoked. A revoked key stays revoked: whoever holds it can also backdate signatures,
+/// so all of them are rejected, old or new. Approving the key again doesn't lift the revocation.
+///
+/// The checks are named `verify_entry` and so on, because the store is an entry itself, and can be
+/// signed and verified with [`Signing`] like any other.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct TrustStore {
+    pub keys: Vec<TrustedKey>,
+    pub revocations: Vec<Revocation>,
+    #[serde(flatten)]
+    pub additional_fields: BTreeMap<String, bson::Bson>,
+}
+
+impl TrustStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts `public_key` from now on, with no end. Approving a key again replaces its label and window.
+    pub fn approve(&mut self, label: &str, public_key: &PublicKey) {
+        self.approve_between(label, public_key, None, None);
+    }
+
+    pub fn approve_between(&mut self, label: &str, public_key: &PublicKey, not_before: Option<bson::DateTime>, not_after: Option<bson::DateTime>) {
+        let public_key = binary(public_key);
+        self.keys.retain(|key| key.public_key != public_key);
+
+        self.keys.push(TrustedKey {
+            public_key,
+            label: label.to_string(),
+            approved: bson::DateTime::now(),
+            not_before,
+            not_after,
+        });
+    }
+
+    /// Forgets `public_key`, as if it had never been approved. Returns `false` if it wasn't.
+    pub fn remove(&mut self, public_key: &PublicKey) -> bool {
+        let before = self.keys.len();
+        self.keys.retain(|key| key.public_key.bytes != public_key.as_slice());
+
+        self.keys.len() != before
+    }
+
+    /// Distrusts `public_key` for good. The key stays listed, so it can't slip back in with an import.
+    pub fn revoke(&mut self, public_key: &PublicKey, reason: Option<&str>) {
+        if self.is_revoked(public_key) {
+            return;
+        }
+
+        self.revocations.push(Revocation {
+            public_key: binary(public_key),
+            revoked: bson::DateTime::now(),
+            reason: reason.map(str::to_string),
+        });
+    }
+
+    pub fn is_revoked(&self, public_key: &PublicKey) -> bool {
+        self.revocations.iter().any(|revocation| revocation.public_key.bytes == public_key.as_slice())
+    }
+
+    pub fn get(&self, public_key: &PublicKey) -> Option<&TrustedKey> {
+        self.keys.iter().find(|key| key.public_key.bytes == public_key.as_slice())
+    }
+
+    pub fn label(&self, public_key: &PublicKey) -> Option<&str> {
+        self.get(public_key).map(|key| key.label.as_str())
+    }
+
+    pub fn check(&self, public_key: &PublicKey) -> Result<&TrustedKey> {
+        self.check_at(public_key, bson::DateTime::now())
+    }
+
+    /// Fails unless `public_key` is approved, valid at `time`, and not revoked.
+    pub fn check_at(&self, public_key: &PublicKey, time: bson::DateTime) -> Result<&TrustedKey> {
+        if self.is_revoked(public_key) {
+            return Err(Error::RevokedKey);
+        }
+
+        let key = self.get(public_key).ok_or(Error::UntrustedSigner)?;
+        if key.not_before.is_some_and(|not_before| time < not_before) || key.not_after.is_some_and(|not_after| time > not_after) {
+            return Err(Error::KeyNotValid);
+        }
+
+        Ok(key)
+    }
+
+    /// The keys that may sign right now.
+    pub fn valid_keys(&self) -> Vec<PublicKey> {
+        self.keys
+            .iter()
+            .filter_map(|key| key_from_slice::<32, PublicKey>(&key.public_key.bytes).ok())
+            .filter(|key| self.check(key).is_ok())
+            .collect()
+    }
+
+    /// Like [`Signing::verify`], but only for signatures by a key of the store.
+    pub fn verify_entry<T: Signing>(&self, signed: T) -> Result<T> {
+        let public_key = embedded_key(&signed, "signing_public_key").ok_or(Error::MissingSignature)??;
+        self.check(&public_key)?;
+
+        signed.verify_with(public_key)
+    }
+
+    /// Like [`Signing::is_secure`], but only for attestations by a key of the store.
+    pub fn is_entry_secure<T: Signing>(&self, entry: &T) -> bool {
+        let Some(Ok(public_key)) = embedded_key(entry, "security_signing_public_key") else { return false };
+
+        self.check(&public_key).is_ok() && entry.is_secure_with(public_key)
+    }
+
+    /// Like [`Signing::verify_threshold`], counting only the keys of the store.
+    pub fn verify_entry_threshold<T: Signing>(&self, signed: T, threshold: usize) -> Result<T> {
+        signed.verify_threshold(&self.valid_keys(), threshold)
+    }
+
+    /// Like [`History::verify_history`], but every revision must be signed by a key of the store
+    /// that is valid now, and was valid when the revision claims to have been made.
+    ///
+    /// The date of a revision is its `modified`, which the signer chose: a key past its
+    /// `NOT_AFTER` could sign revisions dated to before it, so the key must still be valid now.
+    /// Once a key expires, the histories it signed are no longer accepted.
+    pub fn verify_entry_history<T: History>(&self, entry: &T) -> Result<Vec<Revision>> {
+        let revisions = entry.verify_history()?;
+
+        for (index, revision) in revisions.iter().enumerate() {
+            let made = revision
+                .modified()
+                .ok_or_else(|| Error::BrokenHistory(format!("revision {} has no `modified` date", index)))?;
+            let signer = revision.signer()?;
+
+            self.check(&signer)?;
+            self.check_at(&signer, made)?;
+        }
+
+        Ok(revisions)
+    }
+
+    /// Checks a detached signature, and the file it signs, against the keys of the store.
+    pub fn verify_file(&self, signature: &DetachedSignature, path: impl AsRef<Path>) -> Result<()> {
+        let public_key = signature.signer()?;
+        self.check(&public_key)?;
+
+        signature.verify_file(path, &public_key)
+    }
+
+    pub fn to_bytes(self) -> Result<Vec<u8>> {
+        Ok(bson::to_vec(&self.into_entry()?)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_entry(bson::from_slice(bytes)?)
+    }
+
+    pub fn save(self, path: impl AsRef<Path>) -> Result<()> {
+        crate::vault::write_atomic(path.as_ref(), &self.to_bytes()?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+fn binary(public_key: &PublicKey) -> bson::Binary {
+    bson::Binary {
+        subtype: BinarySubtype::Generic,
+        bytes: public_key.to_vec(),
+    }
+}
+
+fn embedded_key<T: Signing>(signed: &T, field: &str) -> Option<Result<PublicKey>> {
+    match signed.get_meta(field) {
+        Some(Bson::Binary(public_key)) => Some(key_from_slice(&public_key.bytes)),
+        _ => None,
+    }
+}
+
+impl Waiter for TrustStore {
+    fn name() -> String {
+        "burrito_trust_store".to_string()
+    }
+
+    fn version() -> String {
+        "0.0.0".to_string()
+    }
+
+    fn into_entry(self) -> Result<Entry> {
+        bson::to_document(&self)?.and_defaults::<Self>()
+    }
+
+    fn from_entry(entry: Entry) -> Result<Self> {
+        let entry = Self::upgrade(entry)?;
+
+        let trust_store = bson::from_document(entry)?;
+
+        Ok(trust_store)
+    }
+}
+
+impl Metadata for TrustStore {
+    fn get_meta(&self, key: &str) -> Option<&bson::Bson> {
+        self.additional_fields.get(key)
+    }
+
+    fn set_meta(&mut self, metadata: (&str, impl Serialize)) -> Result<()> {
+        self.additional_fields.insert(metadata.0.to_string(), bson::to_bson(&metadata.1)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+/*
+ * Copyright (c) 2024.
+ *
+ * Licensed under the MIT license <http://opensource.org/licenses/MIT>.
+ */
+use crate::database::Entry;
+use crate::error::{Error, Result};
+use crate::history::continue_history;
+use crate::keyring::{is_box, Keyring, Opener};
+use crate::signing::SIGNATURE_FIELDS;
+use crate::waiters::burrito_aead_box::BurritoAeadBox;
+use crate::waiters::burrito_box_sym::BurritoBoxSym;
+use crate::waiters::burrito_multi_box::BurritoMultiBox;
+use crate::waiters::burrito_stream_box::BurritoStreamBox;
+use crate::waiters::recursive::Recursive;
+use crate::waiters::Waiter;
+use dryoc::dryocbox::protected::SecretKey;
+
+/// Fields of a box that belong to the ciphertext, and are replaced when it is sealed again.
+const SEALED_FIELDS: &[&str] = &["ENCRYPTED", "MAC", "NONCE", "KDF", "EPHEMERAL_PUBLIC_KEY", "RECIPIENTS", "KEY_ID", "waiter", "version"];
+
+/// What the visitor wants the walker to do after visiting a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    /// Don't descend into this group. Boxes below it are not decrypted.
+    SkipChildren,
+}
+
+/// A node handed to the visitor of [`Walker::walk`].
+pub struct NodeMut<'a> {
+    entry: &'a mut Entry,
+    depth: usize,
+    sealed: bool,
+    boxes: usize,
+    modified: bool,
+}
+
+impl NodeMut<'_> {
+    /// For a group, the group with its `CHILDREN`. They are visited on their own afterwards, as
+    /// the visitor left them, unless it returns [`Flow::SkipChildren`].
+    pub fn entry(&self) -> &Entry {
+        self.entry
+    }
+
+    /// Marks the node as modified, so every box around it is sealed again.
+    pub fn entry_mut(&mut self) -> &mut Entry {
+        self.modified = true;
+        self.entry
+    }
+
+    /// How many groups this node is nested in. Boxes don't count.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// A box that none of the keys could open.
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    /// How many boxes had to be opened to reach this node.
+    pub fn boxes(&self) -> usize {
+        self.boxes
+    }
+}
+
+/// Walks an entry tree depth-first, opening every box it can with the keys of a [`Keyring`], as it
+/// reaches them.
+///
+/// Boxes are transparent: the visitor sees what is inside them, at the position of the box. Boxes
+/// are opened like [`Keyring::decrypt`] does: those no key opens, and stream boxes, are visited
+/// sealed, and malformed ones fail the walk. On the way back up, every box around a modified node
+/// is sealed again, with the key that opened it and a fresh nonce (or ephemeral key, or salt).
+/// Untouched subtrees are returned byte for byte.
+///
+/// Modified nodes with a [history](crate::history) the visitor didn't bring up to date get a new
+/// revision, signed with the key of the keyring that signed the last one. The walk fails if the
+/// keyring doesn't have it.
+pub struct Walker<'k> {
+    keyring: &'k Keyring,
+}
+
+impl<'k> Walker<'k> {
+    pub fn new(keyring: &'k Keyring) -> Self {
+        Self { keyring }
+    }
+
+    pub fn walk(&self, entry: Entry, visit: &mut dyn FnMut(&mut NodeMut) -> Result<Flow>) -> Result<Entry> {
+        let (entry, _modified) = self.walk_node(entry, 0, 0, visit)?;
+
+        Ok(entry)
+    }
+
+    fn walk_node(&self, mut entry: Entry, depth: usize, boxes: usize, visit: &mut dyn FnMut(&mut NodeMut) -> Result<Flow>) -> Result<(Entry, bool)> {
+        let is_box = is_box(&entry);
+
+        // Stream boxes keep their ciphertext somewhere else, and are visited sealed.
+        if is_box && !matches!(entry.get_str("waiter"), Ok(name) if name == BurritoStreamBox::name()) {
+            if let Some((inner, opener)) = self.keyring.open(&entry)? {
+                let (inner, modified) = self.walk_node(inner, depth, boxes + 1, visit)?;
+
+                if !modified {
+                    return Ok((entry, false));
+                }
+
+                let sealed = self.reseal(&entry, inner, opener)?;
+
+                return Ok((continue_history(sealed, self.keyring, self.keyring)?, true));
+            }
+        }
+
+        let mut node = NodeMut {
+            entry: &mut entry,
+            depth,
+            sealed: is_box,
+            boxes,
+            modified: false,
+        };
+        let flow = visit(&mut node)?;
+        let mut modified = node.modified;
+
+        // Whatever children the group has after the visit, including those the visitor added.
+        let children = match flow == Flow::Continue && Recursive::is_group(&entry) {
+            true => entry.get_array_mut("CHILDREN").ok().map(std::mem::take),
+            false => None,
+        };
+
+        if let Some(mut children) = children {
+            for child in children.iter_mut() {
+                let bson::Bson::Document(document) = child else { continue };
+
+                let (walked, child_modified) = self.walk_node(std::mem::take(document), depth + 1, boxes, visit)?;
+                *document = walked;
+                modified |= child_modified;
+            }
+
+            entry.insert("CHILDREN", children);
+        }
+
+        Ok((self.continue_history(entry, modified)?, modified))
+    }
+
+    /// Seals `inner` again the way `original` was sealed, keeping the cleartext metadata of
+    /// `original`. Signatures over the old ciphertext are dropped.
+    ///
+    /// Password boxes get a fresh salt, and so pay for the KDF again. Multi-recipient boxes keep
+    /// their content key, so every recipient can still open them.
+    fn reseal(&self, original: &Entry, inner: Entry, opener: Opener) -> Result<Entry> {
+        let unknown = |name: &str| Error::UnknownKey(name.to_string());
+
+        let mut sealed = match opener {
+            Opener::Symmetric(name) => self.keyring.seal_symmetric(inner, name)?.into_entry()?,
+            Opener::Box(name) => self.keyring.seal_for(inner, name)?.into_entry()?,
+            Opener::Password { name, params } => {
+                let password = self.keyring.password(name).ok_or_else(|| unknown(name))?;
+                BurritoBoxSym::of_secret_with(inner, password, params)?.into_entry()?
+            }
+            Opener::Aead(name) => seal_aead(original, inner, self.keyring.symmetric_key(name).ok_or_else(|| unknown(name))?)?,
+            Opener::Multi(name) => {
+                let secret_key = self.keyring.box_secret_key(name).ok_or_else(|| unknown(name))?;
+                BurritoMultiBox::from_entry(original.clone())?.reseal(&secret_key, inner)?.into_entry()?
+            }
+        };
+
+        keep_metadata(original, &mut sealed);
+
+        Ok(sealed)
+    }
+
+    fn continue_history(&self, entry: Entry, modified: bool) -> Result<Entry> {
+        match modified {
+            true => continue_history(entry, self.keyring, self.keyring),
+            false => Ok(entry),
+        }
+    }
+}
+
+/// Seals `inner` in a new `burrito_aead_box` with `key`. The cleartext metadata of `original` is
+/// authenticated, so it has to be there as the box is sealed.
+pub(crate) fn seal_aead(original: &Entry, inner: Entry, key: SecretKey) -> Result<Entry> {
+    let mut metadata = Entry::new();
+    keep_metadata(original, &mut metadata);
+
+    BurritoAeadBox::encrypt_with_meta(inner, key, metadata.into_iter().collect())?.into_entry()
+}
+
+/// Copies the cleartext metadata of `original` onto `sealed`, a new box for the same contents.
+pub(crate) fn keep_metadata(original: &Entry, sealed: &mut Entry) {
+    for (key, value) in original {
+        if !SEALED_FIELDS.contains(&key.as_str()) && !SIGNATURE_FIELDS.contains(&key.as_str()) {
+            sealed.insert(key, value.clone());
+        }
+    }
+
+    sealed.insert("modified", bson::DateTime::now());
+}